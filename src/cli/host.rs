@@ -0,0 +1,195 @@
+use crate::{
+    cli::{POLL_INTERVAL, parse_impairment, parse_value, read_lines, value},
+    encoding::{
+        CursorMode, SourceKind, Transport, capture::CaptureSource, codec::Codec,
+        layout::CropRegion, network::ClientID,
+    },
+    host::{self, HostSettings, HostingToUIMessage, UIToHostingMessage},
+};
+use std::{
+    collections::VecDeque,
+    io::Write,
+    slice::Iter,
+    sync::mpsc::{self, RecvTimeoutError},
+};
+
+const DEFAULT_PORT: u16 = 1234;
+
+#[derive(Debug, Clone)]
+pub struct HostArguments {
+    pub settings: HostSettings,
+    /// Accepts every client, otherwise the host is asked on stdin
+    pub auto_accept: bool,
+}
+
+pub fn parse_arguments(args: &[String]) -> Result<HostArguments, String> {
+    let mut settings = HostSettings::new(DEFAULT_PORT);
+    let mut auto_accept = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = arg.as_str();
+        match option {
+            "--port" => settings.port = parse_value(option, &mut args)?,
+            "--test-pattern" => {
+                let (width, height) = parse_size(value(option, &mut args)?)?;
+                settings.encoder.capture = CaptureSource::TestPattern { width, height };
+            }
+            "--auto-accept" => auto_accept = true,
+            "--codec" => settings.encoder.codec = parse_codec(value(option, &mut args)?)?,
+            "--bitrate" => settings.encoder.bitrate = parse_value(option, &mut args)?,
+            "--framerate" => settings.encoder.framerate = parse_value(option, &mut args)?,
+            "--rtp" => settings.encoder.transport = Transport::Rtp,
+            "--rtp-destination" => {
+                settings.encoder.transport = Transport::Rtp;
+                settings
+                    .rtp_destinations
+                    .push(parse_value(option, &mut args)?);
+            }
+            "--cursor" => {
+                settings.encoder.cursor_mode = match value(option, &mut args)? {
+                    "hidden" => CursorMode::Hidden,
+                    "embedded" => CursorMode::Embedded,
+                    "metadata" => CursorMode::Metadata,
+                    mode => return Err(format!("Unknown cursor mode {}", mode)),
+                }
+            }
+            "--sources" => {
+                settings.encoder.source_kinds = value(option, &mut args)?
+                    .split(',')
+                    .map(parse_source_kind)
+                    .collect::<Result<_, _>>()?
+            }
+            "--multiple" => settings.encoder.multiple_sources = true,
+            "--crop" => settings.encoder.crop = Some(parse_crop(&mut args)?),
+            "--desktop-audio" => settings.encoder.desktop_audio = true,
+            "--microphone" => settings.encoder.microphone = true,
+            "--max-payload-size" => settings.max_payload_size = parse_value(option, &mut args)?,
+            // Left out of the usage, it is only for seeing how streams cope with a bad network
+            "--impairment" => settings.impairment = parse_impairment(option, &mut args)?,
+            _ => return Err(format!("Unknown option {}", option)),
+        }
+    }
+    Ok(HostArguments {
+        settings,
+        auto_accept,
+    })
+}
+
+/// Accepts the names the window shows, case and dots aside, so both `H.264` and `h264` work
+fn parse_codec(value: &str) -> Result<Codec, String> {
+    Codec::ALL
+        .into_iter()
+        .find(|codec| {
+            codec
+                .name()
+                .replace('.', "")
+                .eq_ignore_ascii_case(&value.replace('.', ""))
+        })
+        .ok_or_else(|| format!("Unknown codec {}", value))
+}
+
+fn parse_source_kind(value: &str) -> Result<SourceKind, String> {
+    match value {
+        "monitor" => Ok(SourceKind::Monitor),
+        "window" => Ok(SourceKind::Window),
+        "virtual" => Ok(SourceKind::Virtual),
+        _ => Err(format!("Unknown source kind {}", value)),
+    }
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or_else(|| format!("A size looks like 1280x720, got {}", value))
+}
+
+fn parse_crop(args: &mut Iter<String>) -> Result<CropRegion, String> {
+    let value = value("--crop", args)?;
+    let numbers: Vec<u32> = value
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid region {}", value))?;
+    let [x, y, width, height] = numbers[..] else {
+        return Err(format!(
+            "A region needs x, y, width and height, got {}",
+            value
+        ));
+    };
+    Ok(CropRegion {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Hosts until `q` is entered or hosting fails, answering join requests by `arguments`' policy
+pub fn run(arguments: HostArguments) -> i32 {
+    let HostArguments {
+        settings,
+        auto_accept,
+    } = arguments;
+    let (hosting_sender, hosting_receiver) = mpsc::channel();
+    let (ui_sender, ui_receiver) = mpsc::channel();
+    let hosting_thread =
+        std::thread::spawn(move || host::host(settings, hosting_sender, ui_receiver));
+
+    let lines = read_lines();
+    // Clients waiting for an answer, the first one is the one asked about
+    let mut pending_clients: VecDeque<ClientID> = VecDeque::new();
+    loop {
+        match hosting_receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(HostingToUIMessage::Listening(port)) => {
+                println!("Hosting on port {}, enter q to stop", port)
+            }
+            Ok(HostingToUIMessage::JoinRequest(client_id)) if auto_accept => {
+                let _ = ui_sender.send(UIToHostingMessage::JoinRequestResponse(client_id, true));
+            }
+            Ok(HostingToUIMessage::JoinRequest(client_id)) => {
+                if pending_clients.is_empty() {
+                    ask_about(client_id);
+                }
+                pending_clients.push_back(client_id);
+            }
+            Ok(HostingToUIMessage::ClientLeft(..)) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        while let Ok(line) = lines.try_recv() {
+            let accepted = match line.trim() {
+                "q" | "quit" => {
+                    let _ = ui_sender.send(UIToHostingMessage::Stop);
+                    continue;
+                }
+                "y" | "yes" => true,
+                "n" | "no" => false,
+                _ => {
+                    if let Some(client_id) = pending_clients.front() {
+                        ask_about(*client_id);
+                    }
+                    continue;
+                }
+            };
+            let Some(client_id) = pending_clients.pop_front() else {
+                continue;
+            };
+            let _ = ui_sender.send(UIToHostingMessage::JoinRequestResponse(client_id, accepted));
+            if let Some(client_id) = pending_clients.front() {
+                ask_about(*client_id);
+            }
+        }
+    }
+
+    // Hosting fails by panicking, for example when no source was picked
+    match hosting_thread.join() {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn ask_about(client_id: ClientID) {
+    print!("Client {} wants to join, accept? [y/n] ", client_id.0);
+    let _ = std::io::stdout().flush();
+}
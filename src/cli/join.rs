@@ -0,0 +1,213 @@
+use crate::{
+    cli::{POLL_INTERVAL, parse_impairment, parse_value, read_lines, value},
+    encoding::{
+        DecodedFrame, FeedID,
+        impairment::ImpairmentProfile,
+        recorder::{RecordTarget, Recorder},
+    },
+    join::{self, JoinedToUIMessage, UIToJoinedMessage},
+};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// How often `--stats` prints
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinArguments {
+    pub address: IpAddr,
+    pub port: u16,
+    pub target: Option<RecordTarget>,
+    /// The feed written to `target`
+    pub feed: FeedID,
+    pub stats: bool,
+    /// Leaves after this long, otherwise stays until `q` is entered or the host is gone
+    pub duration: Option<Duration>,
+    /// Emulated bad network everything the client sends goes through
+    pub impairment: ImpairmentProfile,
+}
+
+pub fn parse_arguments(args: &[String]) -> Result<JoinArguments, String> {
+    let mut positional = Vec::new();
+    let mut target = None;
+    let mut feed = FeedID(0);
+    let mut stats = false;
+    let mut duration = None;
+    let mut impairment = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = arg.as_str();
+        match option {
+            "--record" => target = Some(RecordTarget::File(value(option, &mut args)?.into())),
+            "--video-device" => {
+                target = Some(RecordTarget::VideoDevice(value(option, &mut args)?.into()))
+            }
+            "--feed" => feed = FeedID(parse_value(option, &mut args)?),
+            "--stats" => stats = true,
+            "--duration" => {
+                let seconds: f64 = parse_value(option, &mut args)?;
+                duration = Some(
+                    Duration::try_from_secs_f64(seconds)
+                        .map_err(|_| format!("Invalid value for {}: {}", option, seconds))?,
+                )
+            }
+            // Left out of the usage, it is only for seeing how streams cope with a bad network
+            "--impairment" => impairment = Some(parse_impairment(option, &mut args)?),
+            _ if option.starts_with("--") => return Err(format!("Unknown option {}", option)),
+            _ => positional.push(option),
+        }
+    }
+    let [address, port] = positional[..] else {
+        return Err("join needs the address and port of the host".to_string());
+    };
+    Ok(JoinArguments {
+        address: address
+            .parse()
+            .map_err(|_| format!("Invalid address {}", address))?,
+        port: port.parse().map_err(|_| format!("Invalid port {}", port))?,
+        // Without anything else to do, at least show that frames arrive
+        stats: stats || target.is_none(),
+        target,
+        feed,
+        duration,
+        impairment: impairment.unwrap_or_else(ImpairmentProfile::from_env),
+    })
+}
+
+/// Frames received since the stats were printed last, by feed
+#[derive(Debug)]
+struct Stats {
+    since: Instant,
+    feeds: BTreeMap<u8, (u32, (usize, usize))>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            feeds: BTreeMap::new(),
+        }
+    }
+
+    fn count(&mut self, feed: FeedID, size: (usize, usize)) {
+        let (frames, last_size) = self.feeds.entry(feed.0).or_insert((0, size));
+        *frames += 1;
+        *last_size = size;
+    }
+
+    fn print_if_due(&mut self) {
+        let elapsed = self.since.elapsed();
+        if elapsed < STATS_INTERVAL {
+            return;
+        }
+        for (feed, (frames, (width, height))) in &self.feeds {
+            println!(
+                "Feed {}: {}x{} at {:.1} fps",
+                feed,
+                width,
+                height,
+                *frames as f64 / elapsed.as_secs_f64()
+            );
+        }
+        *self = Stats::new();
+    }
+}
+
+/// Watches the host until `q` is entered, the duration passed or joining failed
+pub fn run(arguments: JoinArguments) -> i32 {
+    let (joined_sender, joined_receiver) = mpsc::channel();
+    let (ui_sender, ui_receiver) = mpsc::channel();
+    let (address, port, impairment) = (arguments.address, arguments.port, arguments.impairment);
+    let join_thread = std::thread::spawn(move || {
+        join::join(address, port, impairment, joined_sender, ui_receiver)
+    });
+
+    let lines = read_lines();
+    let started = Instant::now();
+    let mut recorder: Option<Recorder> = None;
+    let mut stats = Stats::new();
+    let exit_code = loop {
+        if arguments
+            .duration
+            .is_some_and(|duration| started.elapsed() >= duration)
+            || lines
+                .try_iter()
+                .any(|line| matches!(line.trim(), "q" | "quit"))
+        {
+            break 0;
+        }
+
+        match joined_receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(JoinedToUIMessage::JoinRequestResponse(Ok(feeds))) => {
+                println!("Joined, the host shares {} feed(s)", feeds.len());
+                if arguments.target.is_some() && arguments.feed.0 as usize >= feeds.len() {
+                    eprintln!("The host has no feed {}", arguments.feed.0);
+                    break 1;
+                }
+            }
+            Ok(JoinedToUIMessage::JoinRequestResponse(Err(reason))) => {
+                eprintln!("{}", reason);
+                break 1;
+            }
+            Ok(JoinedToUIMessage::Frame(feed, frame)) => {
+                if arguments.stats {
+                    stats.count(feed, (frame.width, frame.height));
+                }
+                if feed == arguments.feed
+                    && let Some(target) = &arguments.target
+                    && let Err(error) = record(frame, target, &mut recorder)
+                {
+                    eprintln!("{}", error);
+                    break 1;
+                }
+            }
+            Ok(JoinedToUIMessage::StreamParameters(feed, stream_parameters)) => println!(
+                "Feed {} is now {}x{}",
+                feed.0, stream_parameters.width, stream_parameters.height
+            ),
+            Ok(JoinedToUIMessage::HostLost) => {
+                eprintln!("The host stopped answering");
+                break 1;
+            }
+            Ok(JoinedToUIMessage::Cursor(_)) | Err(RecvTimeoutError::Timeout) => {}
+            // Joining fails by panicking
+            Err(RecvTimeoutError::Disconnected) => break 1,
+        }
+
+        if arguments.stats {
+            stats.print_if_due();
+        }
+    };
+
+    let _ = ui_sender.send(UIToJoinedMessage::Leave);
+    let _ = join_thread.join();
+    if let Some(recorder) = recorder {
+        recorder.finish();
+    }
+    exit_code
+}
+
+/// Starts recording with the first frame, as its size is the size of the recording
+fn record(
+    frame: DecodedFrame,
+    target: &RecordTarget,
+    recorder: &mut Option<Recorder>,
+) -> Result<(), String> {
+    if recorder.is_none() {
+        *recorder = Some(
+            Recorder::new(target, (frame.width, frame.height))
+                .map_err(|error| format!("Failed to record: {}", error))?,
+        );
+    }
+    // A lost frame is not worth stopping the recording for
+    if let Some(recorder) = recorder
+        && let Err(error) = recorder.push_frame(frame)
+    {
+        eprintln!("Failed to record frame: {:?}", error);
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use crate::encoding::impairment::ImpairmentProfile;
+use std::{
+    io::BufRead,
+    slice::Iter,
+    str::FromStr,
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+pub mod host;
+pub mod join;
+
+/// How often stdin is checked while nothing else happens
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const USAGE: &str = "\
+Usage:
+  quickscreen                  Opens the window
+  quickscreen host [OPTIONS]   Shares the screen without a window
+  quickscreen join ADDRESS PORT [OPTIONS]
+                               Watches a host without a window
+  quickscreen help             Shows this message
+
+Host options:
+  --port PORT                  Port to listen on, 1234 by default
+  --auto-accept                Accepts every client instead of asking on stdin
+  --test-pattern WIDTHxHEIGHT  Shares a test pattern instead of asking the portal
+  --codec CODEC                h264, h265, vp8, vp9 or av1
+  --bitrate KBITS              Target bitrate in kbit/s
+  --framerate FPS              Highest framerate sent
+  --rtp                        Sends plain RTP that players like VLC or ffplay receive
+  --rtp-destination ADDRESS:PORT
+                               Also sends the RTP stream here without a join request, for
+                               players opening the printed session description. Implies --rtp,
+                               can be repeated
+  --cursor MODE                hidden, embedded or metadata
+  --sources KINDS              Comma-separated list of monitor, window and virtual
+  --multiple                   Lets the portal pick several sources
+  --crop X,Y,WIDTH,HEIGHT      Shares only this region of every source
+  --desktop-audio              Shares what the host hears
+  --microphone                 Shares the default microphone
+  --max-payload-size BYTES     Largest amount of frame data put in a single datagram
+
+Join options:
+  --record FILE                Records to a Matroska file, or MP4 when FILE ends in .mp4
+  --video-device DEVICE        Plays into a video output device, like one made by v4l2loopback
+  --feed FEED                  Feed to record when the host shares several, 0 by default
+  --stats                      Prints the framerate of every feed each second, the default
+                               without another output
+  --duration SECONDS           Leaves after this long instead of waiting for q
+";
+
+/// Runs the command in `args`, the arguments after the program name, and returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    let Some((command, args)) = args.split_first() else {
+        return 0;
+    };
+    let result = match command.as_str() {
+        "host" => host::parse_arguments(args).map(host::run),
+        "join" => join::parse_arguments(args).map(join::run),
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        _ => Err(format!("Unknown command {}", command)),
+    };
+    match result {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            2
+        }
+    }
+}
+
+/// The argument following `option`
+fn value<'a>(option: &str, args: &mut Iter<'a, String>) -> Result<&'a str, String> {
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| format!("{} needs a value", option))
+}
+
+fn parse_value<T: FromStr>(option: &str, args: &mut Iter<String>) -> Result<T, String> {
+    let value = value(option, args)?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
+
+/// A profile for the emulated bad network, written like
+/// [`IMPAIRMENT_VARIABLE`](crate::encoding::impairment::IMPAIRMENT_VARIABLE)
+fn parse_impairment(option: &str, args: &mut Iter<String>) -> Result<ImpairmentProfile, String> {
+    value(option, args)?
+        .parse()
+        .map_err(|error| format!("Invalid value for {}: {}", option, error))
+}
+
+/// Lines entered on stdin, read on their own thread so nothing waits for them
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
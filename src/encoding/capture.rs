@@ -0,0 +1,96 @@
+#[cfg(target_os = "linux")]
+use crate::encoding::linux;
+use crate::encoding::{CursorMode, EncoderSettings};
+use gstreamer::glib::object::{Cast, ObjectExt};
+
+/// Where the frames the host shares come from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// Sources the host picks through the desktop portal, captured with PipeWire
+    #[default]
+    Portal,
+    /// A moving test pattern, which needs neither a portal nor a display
+    TestPattern { width: u32, height: u32 },
+}
+
+/// The elements producing the frames of one captured source
+#[derive(Debug)]
+pub(super) struct Capture {
+    /// In linking order, the `caps` property of the last one describes the frames
+    pub elements: Vec<gstreamer::Element>,
+    /// Fed from its own thread once the pipeline plays
+    #[cfg(target_os = "linux")]
+    pub pipewire: Option<(gstreamer_app::AppSrc, linux::PipeWireNode)>,
+}
+
+/// Opens every source to share, for the portal that means asking the host which ones
+pub(super) fn open(
+    settings: &EncoderSettings,
+    framerate: u32,
+    cursor_mode: CursorMode,
+) -> Result<Vec<Capture>, Box<dyn std::error::Error>> {
+    match settings.capture {
+        CaptureSource::Portal => open_portal(settings, framerate, cursor_mode),
+        CaptureSource::TestPattern { width, height } => {
+            Ok(vec![test_pattern(width, height, framerate)?])
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_portal(
+    settings: &EncoderSettings,
+    framerate: u32,
+    cursor_mode: CursorMode,
+) -> Result<Vec<Capture>, Box<dyn std::error::Error>> {
+    let sources = linux::new_sources(
+        framerate,
+        cursor_mode,
+        &settings.source_kinds,
+        settings.multiple_sources,
+    )?;
+    Ok(sources
+        .into_iter()
+        .map(|(source, node)| Capture {
+            elements: vec![source.clone().upcast()],
+            pipewire: Some((source, node)),
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_portal(
+    _settings: &EncoderSettings,
+    _framerate: u32,
+    _cursor_mode: CursorMode,
+) -> Result<Vec<Capture>, Box<dyn std::error::Error>> {
+    Err("Sharing the screen needs the desktop portal, which only Linux has".into())
+}
+
+fn test_pattern(
+    width: u32,
+    height: u32,
+    framerate: u32,
+) -> Result<Capture, Box<dyn std::error::Error>> {
+    // A moving ball, so encoders have changes to encode like with a real screen
+    let source = gstreamer::ElementFactory::make("videotestsrc")
+        .property("is-live", true)
+        .build()?;
+    source.set_property_from_str("pattern", "ball");
+    let caps_filter = gstreamer::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gstreamer_video::VideoCapsBuilder::new()
+                .format(gstreamer_video::VideoFormat::Bgrx)
+                .width(width as i32)
+                .height(height as i32)
+                .framerate(gstreamer::Fraction::new(framerate as i32, 1))
+                .build(),
+        )
+        .build()?;
+    Ok(Capture {
+        elements: vec![source, caps_filter],
+        #[cfg(target_os = "linux")]
+        pipewire: None,
+    })
+}
@@ -0,0 +1,227 @@
+/// Video codecs quickscreen can stream with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    H264,
+    #[default]
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    /// In order of preference, which decides what clients get when the host's codec is not an
+    /// option
+    pub const ALL: [Codec; 5] = [Codec::H265, Codec::H264, Codec::Av1, Codec::Vp9, Codec::Vp8];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::H264 => "H.264",
+            Codec::H265 => "H.265",
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::Av1 => "AV1",
+        }
+    }
+
+    /// Encoder elements, the first one installed is used
+    fn encoders(&self) -> &'static [&'static str] {
+        match self {
+            Codec::H264 => &["x264enc", "openh264enc"],
+            Codec::H265 => &["x265enc"],
+            Codec::Vp8 => &["vp8enc"],
+            Codec::Vp9 => &["vp9enc"],
+            Codec::Av1 => &["svtav1enc", "rav1enc"],
+        }
+    }
+
+    fn decoders(&self) -> &'static [&'static str] {
+        match self {
+            Codec::H264 => &["avdec_h264", "openh264dec"],
+            Codec::H265 => &["avdec_h265", "libde265dec"],
+            Codec::Vp8 => &["vp8dec", "avdec_vp8"],
+            Codec::Vp9 => &["vp9dec", "avdec_vp9"],
+            Codec::Av1 => &["dav1ddec", "av1dec", "avdec_av1"],
+        }
+    }
+
+    /// Turns the encoder output into whole access units
+    pub fn parser(&self) -> Option<&'static str> {
+        match self {
+            Codec::H264 => Some("h264parse"),
+            Codec::H265 => Some("h265parse"),
+            Codec::Vp8 | Codec::Vp9 => None,
+            Codec::Av1 => Some("av1parse"),
+        }
+    }
+
+    pub fn payloader(&self) -> &'static str {
+        match self {
+            Codec::H264 => "rtph264pay",
+            Codec::H265 => "rtph265pay",
+            Codec::Vp8 => "rtpvp8pay",
+            Codec::Vp9 => "rtpvp9pay",
+            Codec::Av1 => "rtpav1pay",
+        }
+    }
+
+    pub fn depayloader(&self) -> &'static str {
+        match self {
+            Codec::H264 => "rtph264depay",
+            Codec::H265 => "rtph265depay",
+            Codec::Vp8 => "rtpvp8depay",
+            Codec::Vp9 => "rtpvp9depay",
+            Codec::Av1 => "rtpav1depay",
+        }
+    }
+
+    /// Name used in RTP caps and session descriptions
+    pub fn rtp_encoding_name(&self) -> &'static str {
+        match self {
+            Codec::H264 => "H264",
+            Codec::H265 => "H265",
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::Av1 => "AV1",
+        }
+    }
+
+    pub fn caps(&self) -> gstreamer::Caps {
+        match self {
+            Codec::H264 | Codec::H265 => {
+                let media_type = if *self == Codec::H264 {
+                    "video/x-h264"
+                } else {
+                    "video/x-h265"
+                };
+                gstreamer::Caps::builder(media_type)
+                    .field("stream-format", "byte-stream")
+                    .field("alignment", "au")
+                    .build()
+            }
+            Codec::Vp8 => gstreamer::Caps::builder("video/x-vp8").build(),
+            Codec::Vp9 => gstreamer::Caps::builder("video/x-vp9").build(),
+            Codec::Av1 => gstreamer::Caps::builder("video/x-av1")
+                .field("stream-format", "obu-stream")
+                .field("alignment", "tu")
+                .build(),
+        }
+    }
+
+    pub fn find_encoder(&self) -> Option<&'static str> {
+        find_element(self.encoders())
+    }
+
+    pub fn find_decoder(&self) -> Option<&'static str> {
+        find_element(self.decoders())
+    }
+
+    /// Codecs with an encoder installed, in the order of [`Codec::ALL`]
+    pub fn available_encoders() -> Vec<Codec> {
+        Self::ALL
+            .into_iter()
+            .filter(|codec| codec.find_encoder().is_some())
+            .collect()
+    }
+
+    /// Codecs with a decoder installed, in the order of [`Codec::ALL`]
+    pub fn available_decoders() -> Vec<Codec> {
+        Self::ALL
+            .into_iter()
+            .filter(|codec| codec.find_decoder().is_some())
+            .collect()
+    }
+
+    /// What a client able to decode `decoders` is sent: `preferred` when it can decode it,
+    /// otherwise the first of the host's `encoders` it can
+    pub fn negotiate(preferred: Codec, encoders: &[Codec], decoders: &[Codec]) -> Option<Codec> {
+        std::iter::once(&preferred)
+            .chain(encoders)
+            .find(|codec| decoders.contains(codec))
+            .copied()
+    }
+
+    /// Builds the encoder tuned for low latency screen content
+    pub fn build_encoder(
+        &self,
+        bitrate: u32,
+        keyframe_interval: u32,
+    ) -> Result<gstreamer::Element, Box<dyn std::error::Error>> {
+        let name = self
+            .find_encoder()
+            .ok_or_else(|| format!("No {} encoder available", self.name()))?;
+        // Saturating is fine, no encoder goes anywhere near 4 Gbit/s
+        let bitrate_bits = bitrate.saturating_mul(1000).to_string();
+        let bitrate = bitrate.to_string();
+        let keyframe_interval = keyframe_interval.to_string();
+
+        // Property types differ between encoders, so they are all set from strings
+        let builder = gstreamer::ElementFactory::make(name);
+        let builder = match name {
+            "x264enc" | "x265enc" => builder
+                .property_from_str("bitrate", &bitrate)
+                .property_from_str("key-int-max", &keyframe_interval)
+                .property_from_str("speed-preset", "ultrafast")
+                .property_from_str("tune", "zerolatency"),
+            "openh264enc" => builder
+                .property_from_str("bitrate", &bitrate_bits)
+                .property_from_str("gop-size", &keyframe_interval),
+            "vp8enc" | "vp9enc" => builder
+                .property_from_str("target-bitrate", &bitrate_bits)
+                .property_from_str("keyframe-max-dist", &keyframe_interval)
+                .property_from_str("deadline", "1")
+                .property_from_str("cpu-used", "8"),
+            "svtav1enc" => builder
+                .property_from_str("target-bitrate", &bitrate)
+                .property_from_str("intra-period-length", &keyframe_interval)
+                .property_from_str("preset", "12"),
+            "rav1enc" => builder
+                .property_from_str("bitrate", &bitrate_bits)
+                .property_from_str("max-key-frame-interval", &keyframe_interval)
+                .property_from_str("low-latency", "true")
+                .property_from_str("speed-preset", "10"),
+            _ => builder,
+        };
+        Ok(builder.build()?)
+    }
+
+    pub fn build_decoder(&self) -> Result<gstreamer::Element, Box<dyn std::error::Error>> {
+        let name = self
+            .find_decoder()
+            .ok_or_else(|| format!("No {} decoder available", self.name()))?;
+        Ok(gstreamer::ElementFactory::make(name).build()?)
+    }
+}
+
+impl From<Codec> for u8 {
+    fn from(value: Codec) -> Self {
+        match value {
+            Codec::H264 => 1,
+            Codec::H265 => 2,
+            Codec::Vp8 => 3,
+            Codec::Vp9 => 4,
+            Codec::Av1 => 5,
+        }
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Codec::H264),
+            2 => Ok(Codec::H265),
+            3 => Ok(Codec::Vp8),
+            4 => Ok(Codec::Vp9),
+            5 => Ok(Codec::Av1),
+            _ => Err(()),
+        }
+    }
+}
+
+fn find_element(names: &[&'static str]) -> Option<&'static str> {
+    names
+        .iter()
+        .copied()
+        .find(|name| gstreamer::ElementFactory::find(name).is_some())
+}
@@ -0,0 +1,327 @@
+use crate::encoding::network::LargeSend;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Set to a profile to send through an emulated bad network, either one of
+/// [`ImpairmentProfile::PRESETS`] or settings like `loss=0.05,delay=40,jitter=20`. The hidden
+/// `--impairment` option of the command line takes the same profiles.
+pub const IMPAIRMENT_VARIABLE: &str = "QUICKSCREEN_IMPAIRMENT";
+/// Datagrams that would wait longer than this for the bandwidth cap are dropped, like a full
+/// router queue does
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(200);
+/// A datagram held back for reordering goes out on its own when nothing follows it for this long
+const MAX_HOLD: Duration = Duration::from_millis(50);
+
+/// How an emulated network mistreats datagrams, chances are between 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImpairmentProfile {
+    pub loss: f64,
+    pub duplication: f64,
+    /// Chance a datagram is held back and sent right after the next one
+    pub reordering: f64,
+    /// Added to every datagram
+    pub delay: Duration,
+    /// Up to this much is added to the delay of every datagram, at random
+    pub jitter: Duration,
+    /// In kbit/s
+    pub bandwidth: Option<u32>,
+}
+
+impl ImpairmentProfile {
+    pub const NONE: ImpairmentProfile = ImpairmentProfile {
+        loss: 0.,
+        duplication: 0.,
+        reordering: 0.,
+        delay: Duration::ZERO,
+        jitter: Duration::ZERO,
+        bandwidth: None,
+    };
+
+    pub const PRESETS: [(&str, ImpairmentProfile); 4] = [
+        ("none", Self::NONE),
+        (
+            "wifi",
+            ImpairmentProfile {
+                loss: 0.01,
+                reordering: 0.005,
+                delay: Duration::from_millis(5),
+                jitter: Duration::from_millis(15),
+                ..Self::NONE
+            },
+        ),
+        (
+            "mobile",
+            ImpairmentProfile {
+                loss: 0.02,
+                reordering: 0.01,
+                delay: Duration::from_millis(60),
+                jitter: Duration::from_millis(40),
+                bandwidth: Some(4000),
+                ..Self::NONE
+            },
+        ),
+        (
+            "congested",
+            ImpairmentProfile {
+                loss: 0.05,
+                duplication: 0.01,
+                delay: Duration::from_millis(30),
+                jitter: Duration::from_millis(10),
+                bandwidth: Some(1000),
+                ..Self::NONE
+            },
+        ),
+    ];
+
+    /// The profile in [`IMPAIRMENT_VARIABLE`], [`ImpairmentProfile::NONE`] when it is not set
+    pub fn from_env() -> Self {
+        let Ok(value) = std::env::var(IMPAIRMENT_VARIABLE) else {
+            return Self::NONE;
+        };
+        match value.parse() {
+            Ok(profile) => {
+                println!("Emulating a bad network: {:?}", profile);
+                profile
+            }
+            Err(error) => {
+                eprintln!("Ignoring {}: {}", IMPAIRMENT_VARIABLE, error);
+                Self::NONE
+            }
+        }
+    }
+}
+
+impl FromStr for ImpairmentProfile {
+    type Err = String;
+
+    /// Either the name of a preset or comma separated settings, with times in milliseconds
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some((_, profile)) = Self::PRESETS.iter().find(|(name, _)| *name == value) {
+            return Ok(*profile);
+        }
+        let mut profile = Self::NONE;
+        for setting in value.split(',') {
+            let (key, number) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Unknown profile {}", setting))?;
+            let number: f64 = number
+                .parse()
+                .ok()
+                .filter(|number: &f64| number.is_finite() && *number >= 0.)
+                .ok_or_else(|| format!("Invalid value for {}: {}", key, number))?;
+            let milliseconds = || {
+                Duration::try_from_secs_f64(number / 1000.)
+                    .map_err(|_| format!("Invalid value for {}: {}", key, number))
+            };
+            let chance = || {
+                (number <= 1.)
+                    .then_some(number)
+                    .ok_or_else(|| format!("{} is a chance between 0 and 1", key))
+            };
+            match key {
+                "loss" => profile.loss = chance()?,
+                "duplication" => profile.duplication = chance()?,
+                "reordering" => profile.reordering = chance()?,
+                "delay" => profile.delay = milliseconds()?,
+                "jitter" => profile.jitter = milliseconds()?,
+                "bandwidth" => profile.bandwidth = Some(number as u32),
+                _ => return Err(format!("Unknown setting {}", key)),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+/// A datagram and where it goes, `None` for the connected address
+type Datagram = (Vec<u8>, Option<SocketAddr>);
+
+/// Datagrams on their way through the emulated network
+#[derive(Debug)]
+struct Link {
+    rng: StdRng,
+    /// By when they arrive, numbered to keep the order of ones arriving at the same time
+    in_flight: BTreeMap<(Instant, u64), Datagram>,
+    sequence: u64,
+    /// Held back to be sent right after the next datagram, or on its own by when it expires
+    held: Option<(Instant, Datagram)>,
+    /// When the bandwidth cap lets the next datagram leave
+    busy_until: Instant,
+}
+
+impl Link {
+    fn chance(&mut self, chance: f64) -> bool {
+        chance > 0. && self.rng.random_bool(chance.min(1.))
+    }
+
+    fn schedule(&mut self, arrival: Instant, datagram: Datagram) {
+        self.sequence += 1;
+        self.in_flight.insert((arrival, self.sequence), datagram);
+    }
+}
+
+/// A UDP socket sending through an emulated bad network, without needing root or `tc`.
+///
+/// Only sent datagrams are impaired, so both ends need one to impair both directions. Delayed
+/// datagrams go out whenever the socket is used next, which the host and join loops do all the
+/// time, or on [`ImpairedSocket::flush`].
+#[derive(Debug)]
+pub struct ImpairedSocket {
+    socket: UdpSocket,
+    profile: ImpairmentProfile,
+    link: Mutex<Link>,
+}
+
+impl ImpairedSocket {
+    pub fn new(socket: UdpSocket, profile: ImpairmentProfile) -> Self {
+        Self::with_rng(socket, profile, StdRng::from_os_rng())
+    }
+
+    /// Impairs the same datagrams every time, for tests
+    pub fn with_seed(socket: UdpSocket, profile: ImpairmentProfile, seed: u64) -> Self {
+        Self::with_rng(socket, profile, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(socket: UdpSocket, profile: ImpairmentProfile, rng: StdRng) -> Self {
+        Self {
+            socket,
+            profile,
+            link: Mutex::new(Link {
+                rng,
+                in_flight: BTreeMap::new(),
+                sequence: 0,
+                held: None,
+                busy_until: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn send_to(&self, datagram: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.impair((datagram.to_vec(), Some(address)))
+    }
+
+    pub fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        self.impair((datagram.to_vec(), None))
+    }
+
+    pub fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.flush();
+        self.socket.recv(buffer)
+    }
+
+    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.flush();
+        self.socket.recv_from(buffer)
+    }
+
+    pub fn connect(&self, address: impl ToSocketAddrs) -> io::Result<()> {
+        self.socket.connect(address)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends every datagram that made it through the emulated network by now, including a held
+    /// back one nothing followed in time. Their senders were told they went out already, so
+    /// failures are only logged, and ones the socket has no room for wait for the next flush.
+    pub fn flush(&self) {
+        let now = Instant::now();
+        let mut link = self.link.lock().unwrap();
+        if link.held.as_ref().is_some_and(|(expiry, _)| *expiry <= now) {
+            let (expiry, held) = link.held.take().unwrap();
+            link.schedule(expiry, held);
+        }
+        while let Some(entry) = link.in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (key, datagram) = entry.remove_entry();
+            match self.send_now(&datagram) {
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    link.in_flight.insert(key, datagram);
+                    break;
+                }
+                Err(error) => eprintln!("Failed to send a delayed datagram: {}", error),
+            }
+        }
+    }
+
+    fn send_now(&self, (datagram, address): &Datagram) -> io::Result<usize> {
+        match address {
+            Some(address) => self.socket.send_to(datagram, address),
+            None => self.socket.send(datagram),
+        }
+    }
+
+    /// Like UDP, datagrams lost on the way still count as sent
+    fn impair(&self, datagram: Datagram) -> io::Result<usize> {
+        let length = datagram.0.len();
+        if self.profile == ImpairmentProfile::NONE {
+            return self.send_now(&datagram);
+        }
+
+        {
+            let profile = self.profile;
+            let mut link = self.link.lock().unwrap();
+            if link.chance(profile.loss) {
+                return Ok(length);
+            }
+
+            let now = Instant::now();
+            let departure = match profile.bandwidth {
+                Some(bandwidth) => {
+                    let start = link.busy_until.max(now);
+                    if start - now > MAX_QUEUE_DELAY {
+                        return Ok(length);
+                    }
+                    let transmission = Duration::from_secs_f64(
+                        length as f64 * 8. / (bandwidth.max(1) as f64 * 1000.),
+                    );
+                    link.busy_until = start + transmission;
+                    link.busy_until
+                }
+                None => now,
+            };
+
+            if link.held.is_none() && link.chance(profile.reordering) {
+                link.held = Some((departure + profile.delay + MAX_HOLD, datagram));
+                return Ok(length);
+            }
+            let arrival = departure + profile.delay + profile.jitter.mul_f64(link.rng.random());
+            if link.chance(profile.duplication) {
+                let duplicate_arrival =
+                    departure + profile.delay + profile.jitter.mul_f64(link.rng.random());
+                link.schedule(duplicate_arrival, datagram.clone());
+            }
+            link.schedule(arrival, datagram);
+            if let Some((_, held)) = link.held.take() {
+                link.schedule(arrival, held);
+            }
+        }
+
+        self.flush();
+        Ok(length)
+    }
+}
+
+impl LargeSend for ImpairedSocket {
+    fn send_datagram_to(&self, datagram: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.send_to(datagram, address)
+    }
+
+    fn recv_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv(buffer)
+    }
+}
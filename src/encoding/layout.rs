@@ -0,0 +1,71 @@
+/// A rectangle of the captured picture, to send instead of all of it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CropRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRegion {
+    /// The part of this region inside a picture of `width` by `height`. Sizes are rounded down to
+    /// even numbers, which encoders subsampling colour by two need.
+    fn clamp_to(&self, width: u32, height: u32) -> CropRegion {
+        let x = self.x.min(width.saturating_sub(2));
+        let y = self.y.min(height.saturating_sub(2));
+        CropRegion {
+            x,
+            y,
+            width: (self.width.min(width.saturating_sub(x)) & !1).max(2),
+            height: (self.height.min(height.saturating_sub(y)) & !1).max(2),
+        }
+    }
+}
+
+/// The part of a captured source that is sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub full_size: (u32, u32),
+    /// All of the source when not cropping
+    pub region: CropRegion,
+}
+
+impl Layout {
+    /// Keeps only what is inside `crop` of a source of `size`
+    pub fn new(size: (u32, u32), crop: Option<CropRegion>) -> Self {
+        let (width, height) = size;
+        let region = match crop {
+            Some(crop) => crop.clamp_to(width, height),
+            None => CropRegion {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+        };
+        Self {
+            full_size: size,
+            region,
+        }
+    }
+
+    /// Pixels cut from the left, top, right and bottom
+    pub fn margins(&self) -> (u32, u32, u32, u32) {
+        let (width, height) = self.full_size;
+        (
+            self.region.x,
+            self.region.y,
+            width.saturating_sub(self.region.x + self.region.width),
+            height.saturating_sub(self.region.y + self.region.height),
+        )
+    }
+
+    /// Moves a position in the source to the sent picture, `None` when it is cut off
+    pub fn translate(&self, (x, y): (i32, i32)) -> Option<(i32, i32)> {
+        let x = x - self.region.x as i32;
+        let y = y - self.region.y as i32;
+        let inside = (0..self.region.width as i32).contains(&x)
+            && (0..self.region.height as i32).contains(&y);
+        inside.then_some((x, y))
+    }
+}
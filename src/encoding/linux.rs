@@ -1,55 +1,913 @@
+use super::{
+    CapturedCursor, CursorBitmap, CursorMode, SourceKind,
+    pacing::{DamageRegion, FramePacer, MAX_DAMAGE_REGIONS, sampled_hash},
+};
 use ashpd::desktop::{
     PersistMode,
-    screencast::{CursorMode, Screencast, SourceType, Stream as ScreencastStream},
+    screencast::{Screencast, SourceType},
+};
+use gstreamer::{
+    glib::{
+        object::ObjectType,
+        translate::{FromGlibPtrFull, IntoGlib},
+    },
+    prelude::{ClockExt, ElementExt, MulDiv},
+};
+use pipewire::{
+    main_loop::MainLoop,
+    properties::properties,
+    spa::buffer::{ChunkFlags, Data, DataType},
+    stream::StreamRef,
 };
-use pipewire::{main_loop::MainLoop, properties::properties};
 use pollster::FutureExt;
-use std::os::fd::OwnedFd;
+use std::{
+    collections::HashSet,
+    os::fd::{BorrowedFd, IntoRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::{Duration, Instant},
+};
+
+/// Used until PipeWire tells us the real size when the node was not picked through the portal
+const DEFAULT_SIZE: (u32, u32) = (1280, 720);
+/// Set to the id of a PipeWire node to capture it instead of asking the portal, for example the
+/// node of `gst-launch-1.0 videotestsrc is-live=true ! pipewiresink`. Several comma separated
+/// ids are sent next to each other.
+const NODE_ID_VARIABLE: &str = "QUICKSCREEN_PIPEWIRE_NODE";
+/// Only linear DMA-BUFs can be imported without knowing the GPU's tiling
+const DRM_FORMAT_MOD_LINEAR: i64 = 0;
+/// Region of interest type of the changed regions attached to captured frames
+pub const DAMAGE_META_TYPE: &str = "damage";
+/// How long PipeWire waits for GStreamer to release a buffer it wants to free
+const RECLAIM_TIMEOUT: Duration = Duration::from_secs(1);
+/// Marks GStreamer memory imported from a [`LentBuffer`]
+const LENT_BUFFER_QUARK: &str = "quickscreen-lent-buffer";
+/// Where the portal's restore token is kept, in the config directory
+const RESTORE_TOKEN_FILE: &str = "quickscreen/restore-token";
+/// Largest cursor image we make room for, in both directions
+const MAX_CURSOR_SIZE: u32 = 128;
+
+/// A PipeWire node to capture and the remote it lives on
+#[derive(Debug)]
+pub struct PipeWireNode {
+    id: u32,
+    /// Remote handed out by the portal, the default PipeWire daemon is used without one
+    fd: Option<OwnedFd>,
+    /// What the compositor does with the cursor, which may differ from what was asked for
+    cursor_mode: CursorMode,
+}
+
+/// A dequeued PipeWire buffer
+struct RawBuffer(*mut pipewire::sys::pw_buffer);
+
+// SAFETY: the pointer is only dereferenced and queued again on the PipeWire thread
+unsafe impl Send for RawBuffer {}
+
+/// A PipeWire buffer GStreamer reads from directly, handed back once every memory imported from
+/// it is dropped
+struct LentBuffer {
+    buffer: *mut pipewire::sys::pw_buffer,
+    returned_buffers: Sender<RawBuffer>,
+}
+
+// SAFETY: the pointer is only sent back to the PipeWire thread, never dereferenced elsewhere
+unsafe impl Send for LentBuffer {}
+unsafe impl Sync for LentBuffer {}
+
+impl Drop for LentBuffer {
+    fn drop(&mut self) {
+        let _ = self.returned_buffers.send(RawBuffer(self.buffer));
+    }
+}
+
+/// Keeps `lent_buffer` lent until `memory` and every memory sharing it are freed
+fn keep_lent(memory: &gstreamer::MemoryRef, lent_buffer: Arc<LentBuffer>) {
+    unsafe extern "C" fn release(lent_buffer: gstreamer::glib::ffi::gpointer) {
+        drop(unsafe { Box::from_raw(lent_buffer as *mut Arc<LentBuffer>) });
+    }
+
+    unsafe {
+        gstreamer::ffi::gst_mini_object_set_qdata(
+            memory.as_mut_ptr() as *mut gstreamer::ffi::GstMiniObject,
+            gstreamer::glib::Quark::from_str(LENT_BUFFER_QUARK).into_glib(),
+            Box::into_raw(Box::new(lent_buffer)) as gstreamer::glib::ffi::gpointer,
+            Some(release),
+        );
+    }
+}
+
+/// Imports the fds of PipeWire buffers as GStreamer memory.
+///
+/// GStreamer gets its own copy of the fd and maps it itself, so the memory stays readable when
+/// PipeWire frees the buffer before GStreamer is done with it.
+struct FdImporter {
+    dma_buf: gstreamer_allocators::DmaBufAllocator,
+    shared_memory: gstreamer_allocators::FdAllocator,
+}
+
+impl FdImporter {
+    fn new() -> Self {
+        Self {
+            dma_buf: gstreamer_allocators::DmaBufAllocator::new(),
+            shared_memory: gstreamer_allocators::FdAllocator::new(),
+        }
+    }
+
+    /// The whole fd of `data`, its content starts `mapoffset` bytes in
+    fn import(&self, data: &Data) -> Option<gstreamer::Memory> {
+        let raw = data.as_raw();
+        let fd = RawFd::try_from(raw.fd).ok().filter(|fd| *fd >= 0)?;
+        // GStreamer closes the fd it is given, PipeWire keeps its own
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }
+            .try_clone_to_owned()
+            .ok()?;
+        let size = raw.mapoffset as usize + raw.maxsize as usize;
+        let memory = match data.type_() {
+            DataType::DmaBuf => unsafe {
+                gstreamer_allocators::ffi::gst_dmabuf_allocator_alloc(
+                    self.dma_buf.as_ptr() as *mut gstreamer::ffi::GstAllocator,
+                    fd.into_raw_fd(),
+                    size,
+                )
+            },
+            DataType::MemFd => unsafe {
+                gstreamer_allocators::ffi::gst_fd_allocator_alloc(
+                    self.shared_memory.as_ptr() as *mut gstreamer::ffi::GstAllocator,
+                    fd.into_raw_fd(),
+                    size,
+                    gstreamer_allocators::ffi::GST_FD_MEMORY_FLAG_NONE,
+                )
+            },
+            _ => return None,
+        };
+        (!memory.is_null()).then(|| unsafe { gstreamer::Memory::from_glib_full(memory) })
+    }
+}
 
 struct FrameData {
     format: pipewire::spa::param::video::VideoInfoRaw,
+    /// Layout of the negotiated format, known once PipeWire picked one
+    video_info: Option<gstreamer_video::VideoInfo>,
+    app_source: gstreamer_app::AppSrc,
+    importer: FdImporter,
+    /// Buffers GStreamer is still reading from, by address
+    lent_buffers: HashSet<usize>,
+    return_sender: Sender<RawBuffer>,
+    returned_buffers: Receiver<RawBuffer>,
+    last_pts: Option<gstreamer::ClockTime>,
+    framerate: u32,
+    pacer: FramePacer,
+    cursor_mode: CursorMode,
+    cursor_sender: Sender<CapturedCursor>,
+    /// Position of the last update sent, to skip buffers where the cursor did not move
+    last_cursor_position: Option<Option<(i32, i32)>>,
+}
+
+impl FrameData {
+    /// Gives buffers GStreamer is done with back to PipeWire
+    fn requeue_returned_buffers(&mut self, stream: &StreamRef) {
+        while let Ok(RawBuffer(buffer)) = self.returned_buffers.try_recv() {
+            if self.lent_buffers.remove(&(buffer as usize)) {
+                unsafe { stream.queue_raw_buffer(buffer) };
+            }
+        }
+    }
+
+    /// Waits a while for GStreamer to be done with `buffer` before PipeWire frees it. GStreamer
+    /// mapped its own copy of the fds, so a buffer it still holds after that stays readable.
+    fn reclaim_buffer(&mut self, stream: &StreamRef, buffer: *mut pipewire::sys::pw_buffer) {
+        let deadline = Instant::now() + RECLAIM_TIMEOUT;
+        while self.lent_buffers.contains(&(buffer as usize)) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.returned_buffers.recv_timeout(remaining) {
+                Ok(RawBuffer(returned)) if returned == buffer => {
+                    self.lent_buffers.remove(&(returned as usize));
+                }
+                Ok(RawBuffer(returned)) => {
+                    if self.lent_buffers.remove(&(returned as usize)) {
+                        unsafe { stream.queue_raw_buffer(returned) };
+                    }
+                }
+                Err(_) => {
+                    eprintln!("GStreamer still holds a buffer PipeWire is freeing");
+                    self.lent_buffers.remove(&(buffer as usize));
+                }
+            }
+        }
+    }
+
+    /// Forwards what the cursor did, if anything
+    fn send_cursor(&mut self, update: CapturedCursor) {
+        if update.bitmap.is_none() && self.last_cursor_position == Some(update.position) {
+            return;
+        }
+        self.last_cursor_position = Some(update.position);
+        let _ = self.cursor_sender.send(update);
+    }
+
+    /// Stamps `frame` with the running time it was captured at, so the encoder's rate control
+    /// sees the real capture rhythm. `capture_time` is the PipeWire timestamp in `CLOCK_MONOTONIC`
+    /// nanoseconds, without one the frame counts as captured now.
+    fn timestamp(
+        &mut self,
+        frame: &mut gstreamer::Buffer,
+        capture_time: Option<i64>,
+        video_info: &gstreamer_video::VideoInfo,
+    ) {
+        let Some(running_time) = self
+            .app_source
+            .clock()
+            .zip(self.app_source.base_time())
+            .and_then(|(clock, base_time)| clock.time().checked_sub(base_time))
+        else {
+            return;
+        };
+        // Only the age of the frame is used, the pipeline clock does not have to be monotonic
+        let age = capture_time
+            .map(|capture_time| {
+                (gstreamer::glib::monotonic_time() * 1000 - capture_time).max(0) as u64
+            })
+            .unwrap_or(0);
+        let mut pts = running_time.saturating_sub(gstreamer::ClockTime::from_nseconds(age));
+        if let Some(last_pts) = self.last_pts {
+            pts = pts.max(last_pts + gstreamer::ClockTime::from_nseconds(1));
+        }
+        self.last_pts = Some(pts);
+
+        let Some(frame) = frame.get_mut() else {
+            return;
+        };
+        frame.set_pts(pts);
+        let fps = video_info.fps();
+        if fps.numer() > 0 {
+            frame.set_duration(
+                gstreamer::ClockTime::SECOND.mul_div_floor(fps.denom() as u64, fps.numer() as u64),
+            );
+        }
+    }
+}
+
+/// Finds metadata of `meta_type` attached to a buffer dequeued with
+/// [`StreamRef::dequeue_raw_buffer`], as an array of as many `T` as fit in it
+///
+/// # Safety
+///
+/// `buffer` has to be non-null, must not have been queued again and `T` has to be the struct
+/// PipeWire uses for `meta_type`
+unsafe fn find_meta<'a, T>(
+    buffer: *mut pipewire::sys::pw_buffer,
+    meta_type: u32,
+) -> Option<&'a [T]> {
+    unsafe {
+        let spa_buffer = (*buffer).buffer;
+        if spa_buffer.is_null() || (*spa_buffer).metas.is_null() {
+            return None;
+        }
+        let metas = std::slice::from_raw_parts((*spa_buffer).metas, (*spa_buffer).n_metas as usize);
+        let meta = metas.iter().find(|meta| {
+            meta.type_ == meta_type
+                && !meta.data.is_null()
+                && meta.size as usize >= std::mem::size_of::<T>()
+        })?;
+        Some(std::slice::from_raw_parts(
+            meta.data as *const T,
+            meta.size as usize / std::mem::size_of::<T>(),
+        ))
+    }
+}
+
+/// Regions the compositor redrew, `None` when it does not tell us
+///
+/// # Safety
+///
+/// `buffer` has to be non-null and must not have been queued again
+unsafe fn find_damage(buffer: *mut pipewire::sys::pw_buffer) -> Option<Vec<DamageRegion>> {
+    let regions = unsafe {
+        find_meta::<pipewire::spa::sys::spa_meta_region>(
+            buffer,
+            pipewire::spa::sys::SPA_META_VideoDamage,
+        )
+    }?;
+    // The list ends at the first empty region
+    Some(
+        regions
+            .iter()
+            .map(|region| region.region)
+            .take_while(|region| region.size.width > 0 && region.size.height > 0)
+            .map(|region| DamageRegion {
+                x: region.position.x,
+                y: region.position.y,
+                width: region.size.width,
+                height: region.size.height,
+            })
+            .collect(),
+    )
+}
+
+/// Reads a `T` at `offset`, if `bytes` is long enough to hold it
+///
+/// # Safety
+///
+/// `T` has to be a plain C struct that is valid for any bit pattern
+unsafe fn read_struct<T>(bytes: &[u8], offset: usize) -> Option<T> {
+    let bytes = bytes.get(offset..offset.checked_add(std::mem::size_of::<T>())?)?;
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// The cursor PipeWire attached to `buffer`, `None` when the buffer says nothing about it
+///
+/// # Safety
+///
+/// `buffer` has to be non-null and must not have been queued again
+unsafe fn find_cursor(buffer: *mut pipewire::sys::pw_buffer) -> Option<CapturedCursor> {
+    use pipewire::spa::sys::{spa_meta_bitmap, spa_meta_cursor};
+
+    let meta = unsafe { find_meta::<u8>(buffer, pipewire::spa::sys::SPA_META_Cursor) }?;
+    let cursor: spa_meta_cursor = unsafe { read_struct(meta, 0) }?;
+    // An id of 0 means the cursor is not over the captured source
+    if cursor.id == 0 {
+        return Some(CapturedCursor {
+            position: None,
+            bitmap: None,
+        });
+    }
+
+    let bitmap_offset = cursor.bitmap_offset as usize;
+    let bitmap = if bitmap_offset >= std::mem::size_of::<spa_meta_cursor>() {
+        unsafe { read_struct::<spa_meta_bitmap>(meta, bitmap_offset) }.and_then(|bitmap| {
+            cursor_bitmap(
+                &meta[bitmap_offset..],
+                &bitmap,
+                (cursor.hotspot.x, cursor.hotspot.y),
+            )
+        })
+    } else {
+        None
+    };
+    Some(CapturedCursor {
+        position: Some((cursor.position.x, cursor.position.y)),
+        bitmap,
+    })
+}
+
+/// Converts a cursor image to RGBA, `bytes` starts at the `spa_meta_bitmap` describing it.
+/// Compositors send an empty image when it did not change.
+fn cursor_bitmap(
+    bytes: &[u8],
+    bitmap: &pipewire::spa::sys::spa_meta_bitmap,
+    hotspot: (i32, i32),
+) -> Option<CursorBitmap> {
+    use pipewire::spa::param::video::VideoFormat;
+
+    let (width, height) = (bitmap.size.width, bitmap.size.height);
+    if width == 0 || height == 0 || width > MAX_CURSOR_SIZE || height > MAX_CURSOR_SIZE {
+        return None;
+    }
+    // Where red, green, blue and alpha are in every pixel
+    let channels = match VideoFormat::from_raw(bitmap.format) {
+        VideoFormat::RGBA => [0, 1, 2, 3],
+        VideoFormat::BGRA => [2, 1, 0, 3],
+        VideoFormat::ARGB => [1, 2, 3, 0],
+        VideoFormat::ABGR => [3, 2, 1, 0],
+        _ => return None,
+    };
+    let row_length = width as usize * 4;
+    let stride = match bitmap.stride {
+        stride if stride > 0 => stride as usize,
+        _ => row_length,
+    };
+    let pixels = bytes.get(bitmap.offset as usize..)?;
+
+    let mut data = Vec::with_capacity(row_length * height as usize);
+    for row in 0..height as usize {
+        let row = pixels.get(row * stride..row * stride + row_length)?;
+        for pixel in row.chunks_exact(4) {
+            data.extend(channels.map(|channel| pixel[channel]));
+        }
+    }
+    Some(CursorBitmap {
+        width: width as u16,
+        height: height as u16,
+        hotspot: (
+            hotspot.0.clamp(0, width as i32) as u16,
+            hotspot.1.clamp(0, height as i32) as u16,
+        ),
+        data,
+    })
+}
+
+/// Exposes the changed regions downstream, for encoders that only send what changed
+fn add_damage_meta(
+    frame: &mut gstreamer::Buffer,
+    damage: &[DamageRegion],
+    video_info: &gstreamer_video::VideoInfo,
+) {
+    let Some(frame) = frame.get_mut() else {
+        return;
+    };
+    for region in damage
+        .iter()
+        .filter_map(|region| region.clip(video_info.width(), video_info.height()))
+    {
+        gstreamer_video::VideoRegionOfInterestMeta::add(
+            frame,
+            DAMAGE_META_TYPE,
+            (
+                region.x as u32,
+                region.y as u32,
+                region.width,
+                region.height,
+            ),
+        );
+    }
+}
+
+/// Maps the formats offered in the `EnumFormat` pod to their GStreamer equivalent
+fn video_format(
+    format: pipewire::spa::param::video::VideoFormat,
+) -> Option<gstreamer_video::VideoFormat> {
+    use gstreamer_video::VideoFormat;
+    use pipewire::spa::param::video::VideoFormat as SpaVideoFormat;
+
+    match format {
+        SpaVideoFormat::RGB => Some(VideoFormat::Rgb),
+        SpaVideoFormat::RGBA => Some(VideoFormat::Rgba),
+        SpaVideoFormat::RGBx => Some(VideoFormat::Rgbx),
+        SpaVideoFormat::BGRx => Some(VideoFormat::Bgrx),
+        SpaVideoFormat::YUY2 => Some(VideoFormat::Yuy2),
+        SpaVideoFormat::I420 => Some(VideoFormat::I420),
+        _ => None,
+    }
+}
+
+/// Describes the frames PipeWire will deliver in the negotiated `format`, after pacing them to
+/// `framerate`
+fn video_info(
+    format: &pipewire::spa::param::video::VideoInfoRaw,
+    framerate: u32,
+) -> Result<gstreamer_video::VideoInfo, gstreamer::glib::BoolError> {
+    let size = format.size();
+    let video_format = video_format(format.format()).ok_or_else(|| {
+        gstreamer::glib::bool_error!("PipeWire picked an unknown format {:?}", format.format())
+    })?;
+
+    gstreamer_video::VideoInfo::builder(video_format, size.width, size.height)
+        .fps(gstreamer::Fraction::new(framerate as i32, 1))
+        .build()
+}
+
+/// Samples the content of every plane, to notice frames identical to the previous one
+fn frame_hash(datas: &mut [Data], video_info: &gstreamer_video::VideoInfo) -> Option<u64> {
+    let planes = plane_layout(datas, video_info)?;
+    let mapped: Vec<Option<&[u8]>> = datas
+        .iter_mut()
+        .map(|data| data.data().map(|slice| &*slice))
+        .collect();
+    let slices = planes
+        .iter()
+        .map(|plane| {
+            let slice = mapped.get(plane.data).copied().flatten()?;
+            Some(&slice[plane.offset..plane.offset + plane.length])
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(sampled_hash(slices))
 }
 
-async fn open_portal() -> ashpd::Result<(ScreencastStream, OwnedFd)> {
+/// Where a plane of a frame lives in a PipeWire buffer
+struct Plane {
+    /// Index in the buffer's datas
+    data: usize,
+    offset: usize,
+    length: usize,
+    stride: i32,
+}
+
+/// Finds every plane in the buffer's datas.
+///
+/// Planes either come in their own [`Data`] or one after the other in the first one, and rows
+/// may be padded.
+fn plane_layout(datas: &mut [Data], video_info: &gstreamer_video::VideoInfo) -> Option<Vec<Plane>> {
+    let n_planes = video_info.n_planes() as usize;
+    let first_stride = datas.first()?.chunk().stride();
+    let mut planes = Vec::with_capacity(n_planes);
+    // Where the next plane starts when all planes share the first data
+    let mut shared_offset = datas.first()?.chunk().offset() as usize;
+
+    for plane in 0..n_planes {
+        let default_stride = video_info.stride()[plane];
+        let (data, offset, stride) = if plane < datas.len() {
+            let chunk = datas[plane].chunk();
+            (plane, chunk.offset() as usize, chunk.stride())
+        } else if first_stride > 0 {
+            let scaled_stride = default_stride * first_stride / video_info.stride()[0];
+            (0, shared_offset, scaled_stride)
+        } else {
+            (0, shared_offset, default_stride)
+        };
+        let stride = if stride > 0 { stride } else { default_stride };
+        let length = stride as usize * video_info.comp_height(plane as u8) as usize;
+        shared_offset = offset + length;
+
+        if (datas[data].as_raw().maxsize as usize) < offset + length {
+            return None;
+        }
+        planes.push(Plane {
+            data,
+            offset,
+            length,
+            stride,
+        });
+    }
+    Some(planes)
+}
+
+/// Describes the actual layout with a `VideoMeta`, so `videoconvert` does not assume the default
+fn add_video_meta(
+    buffer: &mut gstreamer::Buffer,
+    video_info: &gstreamer_video::VideoInfo,
+    offsets: &[usize],
+    strides: &[i32],
+) -> Option<()> {
+    gstreamer_video::VideoMeta::add_full(
+        buffer.get_mut()?,
+        gstreamer_video::VideoFrameFlags::empty(),
+        video_info.format(),
+        video_info.width(),
+        video_info.height(),
+        offsets,
+        strides,
+    )
+    .ok()?;
+    Some(())
+}
+
+/// Copies every plane of a PipeWire buffer into a GStreamer buffer
+fn copy_frame(
+    datas: &mut [Data],
+    video_info: &gstreamer_video::VideoInfo,
+) -> Option<gstreamer::Buffer> {
+    let planes = plane_layout(datas, video_info)?;
+    let mut frame = Vec::new();
+    let mut offsets = Vec::with_capacity(planes.len());
+    for plane in &planes {
+        let slice = datas[plane.data].data()?;
+        offsets.push(frame.len());
+        frame.extend_from_slice(&slice[plane.offset..plane.offset + plane.length]);
+    }
+    let strides: Vec<i32> = planes.iter().map(|plane| plane.stride).collect();
+
+    let mut buffer = gstreamer::Buffer::from_mut_slice(frame);
+    add_video_meta(&mut buffer, video_info, &offsets, &strides)?;
+    Some(buffer)
+}
+
+/// Imports the DMA-BUFs or memfds of a buffer without copying or mapping them
+fn lend_frame(
+    raw_buffer: *mut pipewire::sys::pw_buffer,
+    datas: &mut [Data],
+    video_info: &gstreamer_video::VideoInfo,
+    importer: &FdImporter,
+    returned_buffers: &Sender<RawBuffer>,
+) -> Option<gstreamer::Buffer> {
+    let planes = plane_layout(datas, video_info)?;
+    let used_datas = planes.iter().map(|plane| plane.data).max()? + 1;
+
+    // Every used data becomes one memory, so plane offsets count from the start of their data
+    let mut data_offsets = Vec::with_capacity(used_datas);
+    let mut memories = Vec::with_capacity(used_datas);
+    let mut total_length = 0;
+    for data in &datas[..used_datas] {
+        let memory = importer.import(data)?;
+        data_offsets.push(total_length + data.as_raw().mapoffset as usize);
+        total_length += memory.size();
+        memories.push(memory);
+    }
+
+    let lent_buffer = Arc::new(LentBuffer {
+        buffer: raw_buffer,
+        returned_buffers: returned_buffers.clone(),
+    });
+    let mut buffer = gstreamer::Buffer::new();
+    {
+        let buffer = buffer.get_mut()?;
+        for memory in memories {
+            keep_lent(&memory, lent_buffer.clone());
+            buffer.append_memory(memory);
+        }
+    }
+    let offsets: Vec<usize> = planes
+        .iter()
+        .map(|plane| data_offsets[plane.data] + plane.offset)
+        .collect();
+    let strides: Vec<i32> = planes.iter().map(|plane| plane.stride).collect();
+    add_video_meta(&mut buffer, video_info, &offsets, &strides)?;
+    Some(buffer)
+}
+
+/// The datas of a buffer dequeued with [`StreamRef::dequeue_raw_buffer`]
+///
+/// # Safety
+///
+/// `buffer` has to be non-null and must not have been queued again
+unsafe fn buffer_datas<'a>(buffer: *mut pipewire::sys::pw_buffer) -> &'a mut [Data] {
+    unsafe {
+        let spa_buffer = (*buffer).buffer;
+        if spa_buffer.is_null() || (*spa_buffer).datas.is_null() {
+            return &mut [];
+        }
+        // Data is a transparent wrapper around spa_data
+        std::slice::from_raw_parts_mut(
+            (*spa_buffer).datas as *mut Data,
+            (*spa_buffer).n_datas as usize,
+        )
+    }
+}
+
+fn serialize_pod(object: pipewire::spa::pod::Object) -> Vec<u8> {
+    pipewire::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pipewire::spa::pod::Value::Object(object),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
+
+/// Formats we can handle, with `modifier` only DMA-BUFs with that modifier are accepted
+fn format_pod(modifier: Option<i64>, framerate: u32) -> Vec<u8> {
+    let mut object = pipewire::spa::pod::object!(
+        pipewire::spa::utils::SpaTypes::ObjectParamFormat,
+        pipewire::spa::param::ParamType::EnumFormat,
+        pipewire::spa::pod::property!(
+            pipewire::spa::param::format::FormatProperties::MediaType,
+            Id,
+            pipewire::spa::param::format::MediaType::Video
+        ),
+        pipewire::spa::pod::property!(
+            pipewire::spa::param::format::FormatProperties::MediaSubtype,
+            Id,
+            pipewire::spa::param::format::MediaSubtype::Raw
+        ),
+        pipewire::spa::pod::property!(
+            pipewire::spa::param::format::FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            pipewire::spa::param::video::VideoFormat::RGB,
+            pipewire::spa::param::video::VideoFormat::RGB,
+            pipewire::spa::param::video::VideoFormat::RGBA,
+            pipewire::spa::param::video::VideoFormat::RGBx,
+            pipewire::spa::param::video::VideoFormat::BGRx,
+            pipewire::spa::param::video::VideoFormat::YUY2,
+            pipewire::spa::param::video::VideoFormat::I420,
+        ),
+        pipewire::spa::pod::property!(
+            pipewire::spa::param::format::FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            pipewire::spa::utils::Rectangle {
+                width: 320,
+                height: 240
+            },
+            pipewire::spa::utils::Rectangle {
+                width: 1,
+                height: 1
+            },
+            pipewire::spa::utils::Rectangle {
+                width: 4096,
+                height: 4096
+            }
+        ),
+        pipewire::spa::pod::property!(
+            pipewire::spa::param::format::FormatProperties::VideoFramerate,
+            Choice,
+            Range,
+            Fraction,
+            pipewire::spa::utils::Fraction {
+                num: framerate,
+                denom: 1
+            },
+            pipewire::spa::utils::Fraction { num: 0, denom: 1 },
+            pipewire::spa::utils::Fraction {
+                num: 1000,
+                denom: 1
+            }
+        ),
+    );
+    if let Some(modifier) = modifier {
+        object.properties.push(pipewire::spa::pod::Property {
+            key: pipewire::spa::param::format::FormatProperties::VideoModifier.as_raw(),
+            flags: pipewire::spa::pod::PropertyFlags::MANDATORY,
+            value: pipewire::spa::pod::Value::Long(modifier),
+        });
+    }
+    serialize_pod(object)
+}
+
+/// Asks PipeWire to attach metadata of `meta_type` to every buffer
+fn meta_pod(meta_type: u32, size: usize) -> Vec<u8> {
+    serialize_pod(pipewire::spa::pod::object!(
+        pipewire::spa::utils::SpaTypes::ObjectParamMeta,
+        pipewire::spa::param::ParamType::Meta,
+        pipewire::spa::pod::Property::new(
+            pipewire::spa::sys::SPA_PARAM_META_type,
+            pipewire::spa::pod::Value::Id(pipewire::spa::utils::Id(meta_type)),
+        ),
+        pipewire::spa::pod::Property::new(
+            pipewire::spa::sys::SPA_PARAM_META_size,
+            pipewire::spa::pod::Value::Int(size as i32),
+        ),
+    ))
+}
+
+/// Lets PipeWire hand out DMA-BUFs and memfds, which are imported without copying
+fn buffers_pod() -> Vec<u8> {
+    let data_types = [DataType::MemPtr, DataType::MemFd, DataType::DmaBuf]
+        .iter()
+        .fold(0, |mask, data_type| mask | 1 << data_type.as_raw());
+    serialize_pod(pipewire::spa::pod::object!(
+        pipewire::spa::utils::SpaTypes::ObjectParamBuffers,
+        pipewire::spa::param::ParamType::Buffers,
+        pipewire::spa::pod::Property::new(
+            pipewire::spa::sys::SPA_PARAM_BUFFERS_dataType,
+            pipewire::spa::pod::Value::Int(data_types),
+        ),
+    ))
+}
+
+/// Picks the closest cursor mode the portal offers, falling back to drawing it in the frames
+async fn portal_cursor_mode(
+    proxy: &Screencast<'_>,
+    cursor_mode: CursorMode,
+) -> ashpd::Result<(ashpd::desktop::screencast::CursorMode, CursorMode)> {
+    use ashpd::desktop::screencast::CursorMode as PortalCursorMode;
+
+    let available = proxy.available_cursor_modes().block_on()?;
+    let preferred = match cursor_mode {
+        CursorMode::Hidden => PortalCursorMode::Hidden,
+        CursorMode::Embedded => PortalCursorMode::Embedded,
+        CursorMode::Metadata => PortalCursorMode::Metadata,
+    };
+    if available.contains(preferred) {
+        return Ok((preferred, cursor_mode));
+    }
+    eprintln!(
+        "The portal can't send the cursor as {:?}, it is drawn in the frames instead",
+        cursor_mode
+    );
+    Ok((PortalCursorMode::Embedded, CursorMode::Embedded))
+}
+
+/// Source types the portal can offer out of `source_kinds`, monitors when it offers none of them
+async fn portal_source_types(
+    proxy: &Screencast<'_>,
+    source_kinds: &[SourceKind],
+) -> ashpd::Result<ashpd::enumflags2::BitFlags<SourceType>> {
+    let available = proxy.available_source_types().block_on()?;
+    let requested = source_kinds
+        .iter()
+        .map(|kind| match kind {
+            SourceKind::Monitor => SourceType::Monitor,
+            SourceKind::Window => SourceType::Window,
+            SourceKind::Virtual => SourceType::Virtual,
+        })
+        .fold(
+            ashpd::enumflags2::BitFlags::empty(),
+            |types, source_type| types | source_type,
+        );
+    let types = requested & available;
+    if types.is_empty() {
+        eprintln!(
+            "The portal can't share {:?}, offering monitors instead",
+            source_kinds
+        );
+        return Ok(SourceType::Monitor.into());
+    }
+    Ok(types)
+}
+
+fn restore_token_path() -> PathBuf {
+    gstreamer::glib::user_config_dir().join(RESTORE_TOKEN_FILE)
+}
+
+/// The token letting the portal share the last picked sources again without asking
+fn load_restore_token() -> Option<String> {
+    let token = std::fs::read_to_string(restore_token_path()).ok()?;
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+fn save_restore_token(token: &str) {
+    let path = restore_token_path();
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, token));
+    if let Err(error) = result {
+        eprintln!(
+            "Failed to save the picked source to {}: {}",
+            path.display(),
+            error
+        );
+    }
+}
+
+pub fn has_restore_token() -> bool {
+    restore_token_path().exists()
+}
+
+/// Makes the portal ask what to share again next time
+pub fn forget_restore_token() -> std::io::Result<()> {
+    match std::fs::remove_file(restore_token_path()) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// A stream the host picked in the portal
+struct PortalStream {
+    node_id: u32,
+    size: Option<(u32, u32)>,
+    /// A remote of its own, as every stream runs its own PipeWire context
+    fd: OwnedFd,
+}
+
+async fn open_portal(
+    cursor_mode: CursorMode,
+    source_kinds: &[SourceKind],
+    multiple: bool,
+) -> ashpd::Result<(Vec<PortalStream>, CursorMode)> {
     let proxy = Screencast::new().block_on()?;
     let session = proxy.create_session().block_on()?;
+    let (portal_cursor_mode, cursor_mode) = portal_cursor_mode(&proxy, cursor_mode).block_on()?;
+    let source_types = portal_source_types(&proxy, source_kinds).block_on()?;
+    // The portal falls back to asking when the token no longer matches anything
+    let restore_token = load_restore_token();
     proxy
         .select_sources(
             &session,
-            CursorMode::Hidden,
-            SourceType::Monitor.into(),
-            false,
-            None,
-            PersistMode::DoNot,
+            portal_cursor_mode,
+            source_types,
+            multiple,
+            restore_token.as_deref(),
+            PersistMode::ExplicitlyRevoked,
         )
         .block_on()?;
 
     let response = proxy.start(&session, None).block_on()?.response()?;
-    let stream = response
-        .streams()
-        .first()
-        .expect("no stream found / selected")
-        .to_owned();
-
-    let fd = proxy.open_pipe_wire_remote(&session).block_on()?;
+    // Tokens are single use, every session hands out a new one
+    if let Some(token) = response.restore_token() {
+        save_restore_token(token);
+    }
+    let mut streams = Vec::new();
+    for stream in response.streams() {
+        streams.push(PortalStream {
+            node_id: stream.pipe_wire_node_id(),
+            size: stream
+                .size()
+                .map(|(width, height)| (width as u32, height as u32)),
+            fd: proxy.open_pipe_wire_remote(&session).block_on()?,
+        });
+    }
 
-    Ok((stream, fd))
+    Ok((streams, cursor_mode))
 }
 
 async fn start_pipewire_stream(
-    node_id: u32,
-    fd: OwnedFd,
+    node: PipeWireNode,
     app_source: gstreamer_app::AppSrc,
+    framerate: u32,
+    cursor_sender: Sender<CapturedCursor>,
 ) -> Result<(MainLoop, pipewire::stream::Stream), pipewire::Error> {
     println!("Starting stream");
     pipewire::init();
 
     let mainloop = pipewire::main_loop::MainLoop::new(None)?;
     let context = pipewire::context::Context::new(&mainloop)?;
-    let core = context.connect_fd(fd, None)?;
+    let core = match node.fd {
+        Some(fd) => context.connect_fd(fd, None)?,
+        None => context.connect(None)?,
+    };
 
+    let (return_sender, returned_buffers) = mpsc::channel();
     let data = FrameData {
         format: Default::default(),
+        video_info: None,
+        app_source,
+        importer: FdImporter::new(),
+        lent_buffers: HashSet::new(),
+        return_sender,
+        returned_buffers,
+        last_pts: None,
+        framerate,
+        pacer: FramePacer::new(framerate),
+        cursor_mode: node.cursor_mode,
+        cursor_sender,
+        last_cursor_position: None,
     };
 
     let stream = pipewire::stream::Stream::new(
@@ -67,7 +925,7 @@ async fn start_pipewire_stream(
         .state_changed(|_, _, old, new| {
             println!("State changed: {:?} -> {:?}", old, new);
         })
-        .param_changed(|_, user_data, id, param| {
+        .param_changed(|stream, user_data, id, param| {
             let Some(param) = param else {
                 return;
             };
@@ -109,95 +967,140 @@ async fn start_pipewire_stream(
                 user_data.format.framerate().denom
             );
 
-            // prepare to render video of this size
-        })
-        .process(move |stream, _| match stream.dequeue_buffer() {
-            None => println!("Out of buffers"),
-            Some(mut buffer) => {
-                // println!("Pushing buffer to app_source");
-                let slice = buffer.datas_mut().first_mut().unwrap().data().unwrap();
-                let mut buffer = gstreamer::Buffer::with_size(slice.len()).unwrap();
-                {
-                    let buffer = buffer.get_mut().unwrap();
-                    buffer.copy_from_slice(0, slice).unwrap();
+            // Let the encoder follow rotated monitors and resized windows
+            match video_info(&user_data.format, user_data.framerate).and_then(|video_info| {
+                let caps = video_info.to_caps()?;
+                Ok((video_info, caps))
+            }) {
+                Ok((video_info, caps)) => {
+                    user_data.app_source.set_caps(Some(&caps));
+                    user_data.video_info = Some(video_info);
+                }
+                Err(error) => {
+                    eprintln!("Unsupported video format: {}", error);
+                    user_data.video_info = None;
                 }
-                app_source.push_buffer(buffer).unwrap();
+            }
+
+            let buffers = buffers_pod();
+            let header = meta_pod(
+                pipewire::spa::sys::SPA_META_Header,
+                std::mem::size_of::<pipewire::spa::sys::spa_meta_header>(),
+            );
+            let damage = meta_pod(
+                pipewire::spa::sys::SPA_META_VideoDamage,
+                std::mem::size_of::<pipewire::spa::sys::spa_meta_region>() * MAX_DAMAGE_REGIONS,
+            );
+            let cursor = meta_pod(
+                pipewire::spa::sys::SPA_META_Cursor,
+                std::mem::size_of::<pipewire::spa::sys::spa_meta_cursor>()
+                    + std::mem::size_of::<pipewire::spa::sys::spa_meta_bitmap>()
+                    + (MAX_CURSOR_SIZE * MAX_CURSOR_SIZE * 4) as usize,
+            );
+            let mut params = vec![
+                pipewire::spa::pod::Pod::from_bytes(&buffers).unwrap(),
+                pipewire::spa::pod::Pod::from_bytes(&header).unwrap(),
+                pipewire::spa::pod::Pod::from_bytes(&damage).unwrap(),
+            ];
+            if user_data.cursor_mode == CursorMode::Metadata {
+                params.push(pipewire::spa::pod::Pod::from_bytes(&cursor).unwrap());
+            }
+            if let Err(error) = stream.update_params(&mut params) {
+                eprintln!("Failed to request buffer types and metadata: {}", error);
             }
         })
-        .register()?;
+        .remove_buffer(|stream, user_data, buffer| {
+            user_data.reclaim_buffer(stream, buffer);
+        })
+        .process(|stream, user_data| {
+            user_data.requeue_returned_buffers(stream);
 
-    println!("Created stream {:#?}", stream);
+            let raw_buffer = unsafe { stream.dequeue_raw_buffer() };
+            if raw_buffer.is_null() {
+                println!("Out of buffers");
+                return;
+            }
+            // Cursor moves come without a new picture, so read them before anything is skipped
+            if user_data.cursor_mode == CursorMode::Metadata
+                && let Some(cursor) = unsafe { find_cursor(raw_buffer) }
+            {
+                user_data.send_cursor(cursor);
+            }
+            let Some(video_info) = user_data.video_info.clone() else {
+                unsafe { stream.queue_raw_buffer(raw_buffer) };
+                return;
+            };
+            let datas = unsafe { buffer_datas(raw_buffer) };
+            let capture_time = unsafe {
+                find_meta::<pipewire::spa::sys::spa_meta_header>(
+                    raw_buffer,
+                    pipewire::spa::sys::SPA_META_Header,
+                )
+            }
+            .and_then(|headers| headers.first())
+            .map(|header| header.pts)
+            .filter(|pts| *pts >= 0);
+            let damage = unsafe { find_damage(raw_buffer) };
 
-    let obj = pipewire::spa::pod::object!(
-        pipewire::spa::utils::SpaTypes::ObjectParamFormat,
-        pipewire::spa::param::ParamType::EnumFormat,
-        pipewire::spa::pod::property!(
-            pipewire::spa::param::format::FormatProperties::MediaType,
-            Id,
-            pipewire::spa::param::format::MediaType::Video
-        ),
-        pipewire::spa::pod::property!(
-            pipewire::spa::param::format::FormatProperties::MediaSubtype,
-            Id,
-            pipewire::spa::param::format::MediaSubtype::Raw
-        ),
-        pipewire::spa::pod::property!(
-            pipewire::spa::param::format::FormatProperties::VideoFormat,
-            Choice,
-            Enum,
-            Id,
-            pipewire::spa::param::video::VideoFormat::RGB,
-            pipewire::spa::param::video::VideoFormat::RGB,
-            pipewire::spa::param::video::VideoFormat::RGBA,
-            pipewire::spa::param::video::VideoFormat::RGBx,
-            pipewire::spa::param::video::VideoFormat::BGRx,
-            pipewire::spa::param::video::VideoFormat::YUY2,
-            pipewire::spa::param::video::VideoFormat::I420,
-        ),
-        pipewire::spa::pod::property!(
-            pipewire::spa::param::format::FormatProperties::VideoSize,
-            Choice,
-            Range,
-            Rectangle,
-            pipewire::spa::utils::Rectangle {
-                width: 320,
-                height: 240
-            },
-            pipewire::spa::utils::Rectangle {
-                width: 1,
-                height: 1
-            },
-            pipewire::spa::utils::Rectangle {
-                width: 4096,
-                height: 4096
+            // Compositors send buffers without a new picture when only the cursor moved, and
+            // flag ones they failed to fill
+            let has_picture = datas.iter().any(|data| data.chunk().size() > 0)
+                && !datas
+                    .iter()
+                    .any(|data| data.chunk().flags().contains(ChunkFlags::CORRUPTED));
+            let send = has_picture
+                && user_data
+                    .pacer
+                    .should_send(Instant::now(), damage.as_deref(), || {
+                        // DMA-BUFs are not mapped, frames we can't read always count as changed
+                        frame_hash(datas, &video_info).unwrap_or_else(rand::random)
+                    });
+            if !send {
+                unsafe { stream.queue_raw_buffer(raw_buffer) };
+                return;
             }
-        ),
-        pipewire::spa::pod::property!(
-            pipewire::spa::param::format::FormatProperties::VideoFramerate,
-            Choice,
-            Range,
-            Fraction,
-            pipewire::spa::utils::Fraction { num: 25, denom: 1 },
-            pipewire::spa::utils::Fraction { num: 0, denom: 1 },
-            pipewire::spa::utils::Fraction {
-                num: 1000,
-                denom: 1
+
+            // Keep the buffer until GStreamer is done with it, so the compositor does not
+            // draw the next frame into memory we are still encoding
+            if let Some(mut frame) = lend_frame(
+                raw_buffer,
+                datas,
+                &video_info,
+                &user_data.importer,
+                &user_data.return_sender,
+            ) {
+                user_data.lent_buffers.insert(raw_buffer as usize);
+                user_data.timestamp(&mut frame, capture_time, &video_info);
+                add_damage_meta(&mut frame, user_data.pacer.damage(), &video_info);
+                let _ = user_data.app_source.push_buffer(frame);
+                return;
             }
-        ),
-    );
-    let values: Vec<u8> = pipewire::spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &pipewire::spa::pod::Value::Object(obj),
-    )
-    .unwrap()
-    .0
-    .into_inner();
 
-    let mut params = [pipewire::spa::pod::Pod::from_bytes(&values).unwrap()];
+            match copy_frame(datas, &video_info) {
+                Some(mut frame) => {
+                    user_data.timestamp(&mut frame, capture_time, &video_info);
+                    add_damage_meta(&mut frame, user_data.pacer.damage(), &video_info);
+                    let _ = user_data.app_source.push_buffer(frame);
+                }
+                None => eprintln!("Dropping a frame that does not match the negotiated format"),
+            }
+            unsafe { stream.queue_raw_buffer(raw_buffer) };
+        })
+        .register()?;
+
+    println!("Created stream {:#?}", stream);
+
+    // Linear DMA-BUFs are preferred, shared memory is the fallback
+    let dma_buf_format = format_pod(Some(DRM_FORMAT_MOD_LINEAR), framerate);
+    let shared_memory_format = format_pod(None, framerate);
+    let mut params = [
+        pipewire::spa::pod::Pod::from_bytes(&dma_buf_format).unwrap(),
+        pipewire::spa::pod::Pod::from_bytes(&shared_memory_format).unwrap(),
+    ];
 
     stream.connect(
         pipewire::spa::utils::Direction::Input,
-        Some(node_id),
+        Some(node.id),
         pipewire::stream::StreamFlags::AUTOCONNECT | pipewire::stream::StreamFlags::MAP_BUFFERS,
         &mut params,
     )?;
@@ -208,33 +1111,82 @@ async fn start_pipewire_stream(
     Ok((mainloop, stream))
 }
 
-pub fn new_source()
--> Result<(gstreamer_app::AppSrc, ScreencastStream, OwnedFd), Box<dyn std::error::Error>> {
-    let (stream, fd) = open_portal().block_on()?;
+/// Lets the host pick what to share, returns a source for every picked stream
+pub fn new_sources(
+    framerate: u32,
+    cursor_mode: CursorMode,
+    source_kinds: &[SourceKind],
+    multiple: bool,
+) -> Result<Vec<(gstreamer_app::AppSrc, PipeWireNode)>, Box<dyn std::error::Error>> {
+    let nodes = match std::env::var(NODE_ID_VARIABLE) {
+        Ok(ids) => ids
+            .split(',')
+            .map(|id| {
+                Ok((
+                    PipeWireNode {
+                        id: id.trim().parse()?,
+                        fd: None,
+                        cursor_mode,
+                    },
+                    None,
+                ))
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?,
+        Err(_) => {
+            let (streams, cursor_mode) =
+                open_portal(cursor_mode, source_kinds, multiple).block_on()?;
+            streams
+                .into_iter()
+                .map(|stream| {
+                    (
+                        PipeWireNode {
+                            id: stream.node_id,
+                            fd: Some(stream.fd),
+                            cursor_mode,
+                        },
+                        stream.size,
+                    )
+                })
+                .collect()
+        }
+    };
+    if nodes.is_empty() {
+        return Err("Nothing was picked to share".into());
+    }
+
+    Ok(nodes
+        .into_iter()
+        .map(|(node, size)| (new_source(framerate, size), node))
+        .collect())
+}
 
-    let resolution = stream.size().ok_or("Stream has no size")?;
+fn new_source(framerate: u32, size: Option<(u32, u32)>) -> gstreamer_app::AppSrc {
+    let resolution = size.unwrap_or(DEFAULT_SIZE);
     let video_info = gstreamer_video::VideoInfo::builder(
         gstreamer_video::VideoFormat::Bgrx,
-        resolution.0 as u32,
-        resolution.1 as u32,
+        resolution.0,
+        resolution.1,
     )
-    .fps(gstreamer::Fraction::new(2, 1))
+    .fps(gstreamer::Fraction::new(framerate as i32, 1))
     .build()
     .expect("Failed to create video info");
 
-    let source = gstreamer_app::AppSrc::builder()
+    // Buffers are stamped with their capture time by the PipeWire thread
+    gstreamer_app::AppSrc::builder()
         .caps(&video_info.to_caps().unwrap())
         .format(gstreamer::Format::Time)
-        .build();
-
-    Ok((source, stream, fd))
+        .is_live(true)
+        .build()
 }
 
-pub fn start(source: gstreamer_app::AppSrc, stream: ScreencastStream, fd: OwnedFd) {
-    let pipewire_node_id = stream.pipe_wire_node_id();
-
+pub fn start(
+    source: gstreamer_app::AppSrc,
+    node: PipeWireNode,
+    framerate: u32,
+    cursor_sender: Sender<CapturedCursor>,
+) {
     std::thread::spawn(move || {
-        start_pipewire_stream(pipewire_node_id, fd, source)
+        start_pipewire_stream(node, source, framerate, cursor_sender)
             .block_on()
             .unwrap();
     });
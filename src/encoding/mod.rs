@@ -1,82 +1,1008 @@
 use gstreamer::{
     Pipeline,
     glib::object::Cast,
-    prelude::{ElementExt, GstBinExtManual},
+    glib::object::ObjectExt,
+    prelude::{
+        ClockExt, ElementExt, ElementExtManual, GstBinExtManual, PadExt, PadExtManual, PipelineExt,
+    },
+};
+use gstreamer_app::{AppSink, AppSinkCallbacks, AppSrc};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::Duration,
 };
-use std::sync::Mutex;
 
+pub mod capture;
+pub mod codec;
+pub mod impairment;
+pub mod layout;
 #[cfg(target_os = "linux")]
 pub mod linux;
 pub mod network;
+pub mod pacing;
+pub mod recorder;
+pub mod rtp;
+
+use capture::{Capture, CaptureSource};
+use codec::Codec;
+use layout::{CropRegion, Layout};
+
+/// In kbit/s
+const DEFAULT_BITRATE: u32 = 2000;
+/// Every this many frames a keyframe is sent, so viewers recover from lost frames
+const DEFAULT_KEYFRAME_INTERVAL: u32 = 30;
+const DEFAULT_FRAMERATE: u32 = 30;
+const MAX_QUEUED_FRAMES: u32 = 4;
+/// RTP packets are only dropped once this many wait for the host loop, a few frames worth
+const MAX_QUEUED_PACKETS: u32 = 256;
+/// About a third of a second of audio
+const MAX_QUEUED_AUDIO_PACKETS: usize = 16;
+/// In bit/s
+const AUDIO_BITRATE: i32 = 96_000;
+/// Opus always runs at 48 kHz
+const AUDIO_RATE: i32 = 48_000;
+const AUDIO_CHANNELS: i32 = 2;
+/// Time packets get to arrive before they are played, audio needs it to play without gaps
+const PLAYBACK_DELAY: gstreamer::ClockTime = gstreamer::ClockTime::from_mseconds(100);
+
+/// How encoded video is put on the wire
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Whole access units sent as quickscreen fragments
+    #[default]
+    Quickscreen,
+    /// Plain RTP packets that standard players can receive
+    Rtp,
+}
+
+/// What the host can share, the portal asks which one exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Monitor,
+    Window,
+    /// A virtual monitor created for the stream
+    Virtual,
+}
+
+impl SourceKind {
+    pub const ALL: [SourceKind; 3] = [SourceKind::Monitor, SourceKind::Window, SourceKind::Virtual];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourceKind::Monitor => "Monitor",
+            SourceKind::Window => "Window",
+            SourceKind::Virtual => "Virtual monitor",
+        }
+    }
+}
+
+/// How the host's cursor reaches viewers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CursorMode {
+    Hidden,
+    /// Drawn into the captured frames by the compositor
+    #[default]
+    Embedded,
+    /// Sent next to the frames and drawn by the viewer, so it moves smoothly even when few
+    /// frames are sent
+    Metadata,
+}
+
+impl CursorMode {
+    pub const ALL: [CursorMode; 3] = [
+        CursorMode::Hidden,
+        CursorMode::Embedded,
+        CursorMode::Metadata,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CursorMode::Hidden => "Hidden",
+            CursorMode::Embedded => "In the video",
+            CursorMode::Metadata => "Drawn by viewers",
+        }
+    }
+}
+
+/// A cursor image in RGBA, without premultiplied alpha
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorBitmap {
+    pub width: u16,
+    pub height: u16,
+    /// The pixel of the image that points at the cursor position
+    pub hotspot: (u16, u16),
+    pub data: Vec<u8>,
+}
+
+/// Where the cursor is, sent in [`CursorMode::Metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorUpdate {
+    /// The feed the cursor is over and its position in it, `None` while it is outside of every
+    /// feed
+    pub position: Option<(FeedID, i32, i32)>,
+    /// Only set when the cursor image changed
+    pub bitmap: Option<CursorBitmap>,
+}
+
+impl CursorUpdate {
+    /// Combines `self` with a later update, keeping the newest image either of them carried
+    pub fn then(self, later: CursorUpdate) -> CursorUpdate {
+        CursorUpdate {
+            position: later.position,
+            bitmap: later.bitmap.or(self.bitmap),
+        }
+    }
+}
+
+/// The cursor as a captured source reports it
+#[derive(Debug)]
+struct CapturedCursor {
+    /// Relative to the whole source, `None` while the cursor is outside of it
+    position: Option<(i32, i32)>,
+    bitmap: Option<CursorBitmap>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderSettings {
+    pub capture: CaptureSource,
+    pub transport: Transport,
+    /// Preferred codec, another one is used when it has no encoder installed. Clients that
+    /// can't decode it get another codec both sides support.
+    pub codec: Codec,
+    /// In kbit/s
+    pub bitrate: u32,
+    pub keyframe_interval: u32,
+    /// Captured frames are paced to this rate, between [`pacing::MIN_FRAMERATE`] and
+    /// [`pacing::MAX_FRAMERATE`]
+    pub framerate: u32,
+    /// Only [`CursorMode::Hidden`] and [`CursorMode::Embedded`] are possible over RTP
+    pub cursor_mode: CursorMode,
+    /// Kinds of sources offered in the portal's picker
+    pub source_kinds: Vec<SourceKind>,
+    /// Lets the host pick several sources, which are sent as separate feeds. Over RTP only the
+    /// first one is sent.
+    pub multiple_sources: bool,
+    /// Only this part of every source is sent
+    pub crop: Option<CropRegion>,
+    /// Shares what the host hears, the monitor of the default output. Audio is not sent over
+    /// RTP.
+    pub desktop_audio: bool,
+    /// Mixes the default microphone into the shared audio
+    pub microphone: bool,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            capture: CaptureSource::default(),
+            transport: Transport::default(),
+            codec: Codec::default(),
+            bitrate: DEFAULT_BITRATE,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            framerate: DEFAULT_FRAMERATE,
+            cursor_mode: CursorMode::default(),
+            source_kinds: vec![SourceKind::Monitor],
+            multiple_sources: false,
+            crop: None,
+            desktop_audio: false,
+            microphone: false,
+        }
+    }
+}
+
+impl From<Transport> for u8 {
+    fn from(value: Transport) -> Self {
+        match value {
+            Transport::Quickscreen => 1,
+            Transport::Rtp => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Transport {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Transport::Quickscreen),
+            2 => Ok(Transport::Rtp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Everything a client needs to know to decode the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamParameters {
+    pub codec: Codec,
+    pub transport: Transport,
+    pub width: u16,
+    pub height: u16,
+    pub framerate: u16,
+}
+
+impl StreamParameters {
+    fn new(
+        codec: Codec,
+        transport: Transport,
+        layout: &Layout,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> Self {
+        Self {
+            codec,
+            transport,
+            width: layout.region.width as u16,
+            height: layout.region.height as u16,
+            framerate: (video_info.fps().numer() / video_info.fps().denom().max(1)) as u16,
+        }
+    }
+}
 
-pub const RESOLUTION: (usize, usize) = (1920, 1080);
-const BITRATE: u32 = 256;
+/// Identifies one of the sources the host shares, numbered from 0 in the order they were picked
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct FeedID(pub u8);
 
 #[derive(Debug)]
 pub struct NetworkFrame {
+    pub feed: FeedID,
+    pub data: Vec<u8>,
+    /// Running time on the host when the frame was captured
+    pub pts: Option<gstreamer::ClockTime>,
+}
+
+/// Opus encoded audio, all captured sources mixed together
+#[derive(Debug)]
+pub struct AudioPacket {
     pub data: Vec<u8>,
+    /// Running time on the host when the audio was captured, comparable with the frames' one
+    pub pts: Option<gstreamer::ClockTime>,
+}
+
+/// A raw BGRx frame as produced by the [`Decoder`]
+#[derive(Debug)]
+pub struct DecodedFrame {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    /// Running time on the host when the frame was captured
+    pub pts: Option<gstreamer::ClockTime>,
+}
+
+/// Whether the sources picked last time are shared again without asking
+pub fn has_saved_source() -> bool {
+    #[cfg(target_os = "linux")]
+    let saved = linux::has_restore_token();
+    #[cfg(not(target_os = "linux"))]
+    let saved = false;
+    saved
+}
+
+/// Makes the next [`Encoder`] ask what to share again
+pub fn forget_saved_source() -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    linux::forget_restore_token()?;
+    Ok(())
+}
+
+/// One captured source and the part of the pipeline preparing it for the encoders
+#[derive(Debug)]
+struct Feed {
+    /// Last element of the capture, its `caps` property describes the frames
+    source: gstreamer::Element,
+    video_crop: Option<gstreamer::Element>,
+    /// Hands the converted frames to a [`Branch`] for every codec in use
+    tee: gstreamer::Element,
+    layout: Layout,
+    stream_parameters: StreamParameters,
+    cursor_updates: Receiver<CapturedCursor>,
+    cursor_position: Option<(i32, i32)>,
+}
+
+impl Feed {
+    /// Crops the source at its current size, returns whether the [`StreamParameters`] changed
+    fn update(&mut self, crop: Option<CropRegion>) -> bool {
+        let Some(video_info) = self
+            .source
+            .property::<Option<gstreamer::Caps>>("caps")
+            .and_then(|caps| gstreamer_video::VideoInfo::from_caps(&caps).ok())
+        else {
+            return false;
+        };
+        let layout = Layout::new((video_info.width(), video_info.height()), crop);
+        if let Some(video_crop) = &self.video_crop
+            && layout != self.layout
+        {
+            let (left, top, right, bottom) = layout.margins();
+            video_crop.set_property("left", left as i32);
+            video_crop.set_property("top", top as i32);
+            video_crop.set_property("right", right as i32);
+            video_crop.set_property("bottom", bottom as i32);
+        }
+        self.layout = layout;
+
+        let stream_parameters = StreamParameters::new(
+            self.stream_parameters.codec,
+            self.stream_parameters.transport,
+            &self.layout,
+            &video_info,
+        );
+        if stream_parameters == self.stream_parameters {
+            return false;
+        }
+        self.stream_parameters = stream_parameters;
+        true
+    }
+}
+
+/// The elements encoding a feed with one codec, behind a pad of the feed's tee
+#[derive(Debug)]
+struct Branch {
+    tee_pad: gstreamer::Pad,
+    elements: Vec<gstreamer::Element>,
 }
 
 #[derive(Debug)]
 pub struct Encoder {
     pub pipeline: Pipeline,
-    pub sink: gstreamer::Element,
-    pub frame_index: Mutex<u64>,
+    settings: EncoderSettings,
+    /// Indexed by [`FeedID`]
+    feeds: Vec<Feed>,
+    /// Every feed is always encoded with it, the one in the [`StreamParameters`]
+    codec: Codec,
+    /// Further codecs for clients that can't decode [`Encoder::codec`], with a branch for every
+    /// feed
+    extra_encodings: HashMap<Codec, Vec<Branch>>,
+    /// Encoded frames of every feed and codec, in the order they were encoded
+    frames: Receiver<(Codec, NetworkFrame)>,
+    frame_sender: SyncSender<(Codec, NetworkFrame)>,
+    /// `None` when no audio is shared
+    audio: Option<Receiver<AudioPacket>>,
 }
 
 impl Encoder {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(settings: &EncoderSettings) -> Result<Self, Box<dyn std::error::Error>> {
         gstreamer::init()?;
         let pipeline = gstreamer::Pipeline::new();
 
+        let framerate = settings
+            .framerate
+            .clamp(pacing::MIN_FRAMERATE, pacing::MAX_FRAMERATE);
+        // Players receiving plain RTP have no way to draw the cursor themselves
+        let cursor_mode = match (settings.transport, settings.cursor_mode) {
+            (Transport::Rtp, CursorMode::Metadata) => CursorMode::Embedded,
+            (_, cursor_mode) => cursor_mode,
+        };
+        let mut captures = capture::open(settings, framerate, cursor_mode)?;
+        // Players only expect a single video on the port they listen on
+        if settings.transport == Transport::Rtp && captures.len() > 1 {
+            println!("Only the first picked source is sent over RTP");
+            captures.truncate(1);
+        }
+
+        let codec = if settings.codec.find_encoder().is_some() {
+            settings.codec
+        } else {
+            Codec::available_encoders()
+                .into_iter()
+                .next()
+                .ok_or("No supported video encoder available")?
+        };
+        println!("Encoding with {}", codec.name());
+
+        let queue_length = match settings.transport {
+            Transport::Quickscreen => MAX_QUEUED_FRAMES,
+            Transport::Rtp => MAX_QUEUED_PACKETS,
+        };
+        let (frame_sender, frames) = mpsc::sync_channel(queue_length as usize);
+
+        let mut feeds = Vec::with_capacity(captures.len());
+        let mut captures_to_start = Vec::with_capacity(captures.len());
+        for (index, capture) in captures.into_iter().enumerate() {
+            let (video_crop, tee) = build_feed(&pipeline, &capture, settings)?;
+            build_branch(
+                &pipeline,
+                &tee,
+                FeedID(index as u8),
+                codec,
+                settings,
+                frame_sender.clone(),
+            )?;
+            let source = capture
+                .elements
+                .last()
+                .ok_or("Capture has no elements")?
+                .clone();
+            let caps = source
+                .property::<Option<gstreamer::Caps>>("caps")
+                .ok_or("Source has no caps")?;
+            let (cursor_sender, cursor_updates) = mpsc::channel();
+            let mut feed = Feed {
+                stream_parameters: StreamParameters::new(
+                    codec,
+                    settings.transport,
+                    &Layout::default(),
+                    &gstreamer_video::VideoInfo::from_caps(&caps)?,
+                ),
+                source,
+                video_crop,
+                tee,
+                layout: Layout::default(),
+                cursor_updates,
+                cursor_position: None,
+            };
+            feed.update(settings.crop);
+            feeds.push(feed);
+            captures_to_start.push((capture, cursor_sender));
+        }
+
+        let audio = if settings.transport == Transport::Quickscreen
+            && (settings.desktop_audio || settings.microphone)
+        {
+            let (audio_sender, audio) = mpsc::sync_channel(MAX_QUEUED_AUDIO_PACKETS);
+            build_audio(
+                &pipeline,
+                settings.desktop_audio,
+                settings.microphone,
+                audio_sender,
+            )?;
+            Some(audio)
+        } else {
+            None
+        };
+
+        pipeline.set_state(gstreamer::State::Playing).unwrap();
+
         #[cfg(target_os = "linux")]
-        let (source, stream, fd) = linux::new_source()?;
+        for (capture, cursor_sender) in captures_to_start {
+            if let Some((source, node)) = capture.pipewire {
+                linux::start(source, node, framerate, cursor_sender);
+            }
+        }
 
-        let video_convert = gstreamer::ElementFactory::make("videoconvert").build()?;
+        Ok(Self {
+            pipeline,
+            settings: settings.clone(),
+            feeds,
+            codec,
+            extra_encodings: HashMap::new(),
+            frames,
+            frame_sender,
+            audio,
+        })
+    }
+
+    /// The codec every feed is encoded with, see [`Encoder::encode_with`] for others
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Starts encoding every feed with `codec` too, if it is not already
+    pub fn encode_with(&mut self, codec: Codec) -> Result<(), Box<dyn std::error::Error>> {
+        if codec == self.codec || self.extra_encodings.contains_key(&codec) {
+            return Ok(());
+        }
+        println!("Also encoding with {}", codec.name());
+        let mut branches = Vec::with_capacity(self.feeds.len());
+        let result = self.feeds.iter().enumerate().try_for_each(
+            |(index, feed)| -> Result<(), Box<dyn std::error::Error>> {
+                let branch = build_branch(
+                    &self.pipeline,
+                    &feed.tee,
+                    FeedID(index as u8),
+                    codec,
+                    &self.settings,
+                    self.frame_sender.clone(),
+                )?;
+                let started = branch
+                    .elements
+                    .iter()
+                    .try_for_each(|element| element.sync_state_with_parent());
+                branches.push(branch);
+                started?;
+                Ok(())
+            },
+        );
+        if let Err(error) = result {
+            for branch in branches {
+                remove_branch(&self.pipeline, branch);
+            }
+            return Err(error);
+        }
+        self.extra_encodings.insert(codec, branches);
+        Ok(())
+    }
+
+    /// Stops encoding with the codecs besides [`Encoder::codec`] that are not `needed` anymore
+    pub fn keep_encodings(&mut self, needed: &[Codec]) {
+        let unneeded: Vec<Codec> = self
+            .extra_encodings
+            .keys()
+            .filter(|codec| !needed.contains(codec))
+            .copied()
+            .collect();
+        for codec in unneeded {
+            println!("Stopped encoding with {}", codec.name());
+            for branch in self.extra_encodings.remove(&codec).unwrap() {
+                remove_branch(&self.pipeline, branch);
+            }
+        }
+    }
 
-        let encoder = gstreamer::ElementFactory::make("x265enc")
-            .property("bitrate", BITRATE)
-            .property("key-int-max", 1)
-            .build()?;
+    /// How every feed is encoded, indexed by [`FeedID`]
+    pub fn stream_parameters(&self) -> Vec<StreamParameters> {
+        self.feeds
+            .iter()
+            .map(|feed| feed.stream_parameters)
+            .collect()
+    }
 
-        let parser = gstreamer::ElementFactory::make("h265parse").build()?;
+    /// Picks up size and framerate changes of the captured sources, returns the feeds whose
+    /// [`StreamParameters`] changed
+    pub fn update_stream_parameters(&mut self) -> Vec<(FeedID, StreamParameters)> {
+        let crop = self.settings.crop;
+        self.feeds
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, feed)| feed.update(crop))
+            .map(|(index, feed)| (FeedID(index as u8), feed.stream_parameters))
+            .collect()
+    }
 
-        let queue = gstreamer::ElementFactory::make("queue").build()?;
+    /// Everything the cursor did since the last call, merged into one update with the position
+    /// in the sent picture
+    pub fn pull_cursor(&mut self) -> Option<CursorUpdate> {
+        let mut changed = false;
+        let mut bitmap = None;
+        for feed in &mut self.feeds {
+            for cursor in feed.cursor_updates.try_iter() {
+                changed = true;
+                feed.cursor_position = cursor.position;
+                bitmap = cursor.bitmap.or(bitmap);
+            }
+        }
+        if !changed {
+            return None;
+        }
+        // The cursor is over at most one source, the others report it as hidden
+        let position = self.feeds.iter().enumerate().find_map(|(index, feed)| {
+            let (x, y) = feed.layout.translate(feed.cursor_position?)?;
+            Some((FeedID(index as u8), x, y))
+        });
+        Some(CursorUpdate { position, bitmap })
+    }
 
-        // let sink = gstreamer::ElementFactory::make("udpsink")
-        //     .property("host", "0.0.0.0")
-        //     .property("port", 1234)
-        //     .build()?;
+    /// Waits at most `timeout` for the next encoded access unit of any feed, along with the codec
+    /// it is encoded with
+    pub fn pull_frame(&self, timeout: gstreamer::ClockTime) -> Option<(Codec, NetworkFrame)> {
+        self.frames
+            .recv_timeout(Duration::from_nanos(timeout.nseconds()))
+            .ok()
+    }
 
-        let sink = gstreamer::ElementFactory::make("autovideosink").build()?;
+    /// The next encoded audio packet, if one is ready
+    pub fn pull_audio(&self) -> Option<AudioPacket> {
+        self.audio.as_ref()?.try_recv().ok()
+    }
+}
 
-        pipeline.add_many(&[
-            source.upcast_ref(),
-            &video_convert,
-            &encoder,
-            &parser,
-            &queue,
-            &sink,
-        ])?;
+/// Adds the elements preparing `capture` for the encoders to the pipeline, returns the element
+/// cropping it when the host only shares a region and the tee the encoders are linked to
+fn build_feed(
+    pipeline: &Pipeline,
+    capture: &Capture,
+    settings: &EncoderSettings,
+) -> Result<(Option<gstreamer::Element>, gstreamer::Element), Box<dyn std::error::Error>> {
+    let mut elements = capture.elements.clone();
 
-        gstreamer::Element::link_many(&[
-            source.upcast_ref(),
-            &video_convert,
-            // &encoder,
-            // &parser,
-            // &queue,
-            &sink,
-        ])?;
+    let video_crop = match settings.crop {
+        Some(_) => Some(gstreamer::ElementFactory::make("videocrop").build()?),
+        None => None,
+    };
+    elements.extend(video_crop.clone());
 
-        pipeline.set_state(gstreamer::State::Playing).unwrap();
+    let video_convert = gstreamer::ElementFactory::make("videoconvert").build()?;
+    let tee = gstreamer::ElementFactory::make("tee").build()?;
+    elements.extend([video_convert, tee.clone()]);
 
-        #[cfg(target_os = "linux")]
-        linux::start(source, stream, fd);
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+    Ok((video_crop, tee))
+}
+
+/// Adds the elements encoding `feed` with `codec` to the pipeline, behind a new pad of `tee`
+fn build_branch(
+    pipeline: &Pipeline,
+    tee: &gstreamer::Element,
+    feed: FeedID,
+    codec: Codec,
+    settings: &EncoderSettings,
+    frame_sender: SyncSender<(Codec, NetworkFrame)>,
+) -> Result<Branch, Box<dyn std::error::Error>> {
+    // Keeps a slow encoder from holding up the others
+    let mut elements = vec![gstreamer::ElementFactory::make("queue").build()?];
+
+    let encoder = codec.build_encoder(settings.bitrate, settings.keyframe_interval)?;
+
+    let queue = gstreamer::ElementFactory::make("queue").build()?;
+
+    elements.push(encoder);
+    if let Some(parser) = codec.parser() {
+        elements.push(gstreamer::ElementFactory::make(parser).build()?);
+    }
+    elements.push(queue);
+
+    let sink_caps = match settings.transport {
+        Transport::Quickscreen => codec.caps(),
+        Transport::Rtp => {
+            let payloader = gstreamer::ElementFactory::make(codec.payloader())
+                .property("pt", rtp::PAYLOAD_TYPE)
+                .property("mtu", rtp::MTU)
+                .build()?;
+            if payloader.has_property("config-interval") {
+                payloader.set_property("config-interval", -1);
+            }
+            elements.push(payloader);
+            rtp::caps(codec)
+        }
+    };
+    let sink = AppSink::builder()
+        .caps(&sink_caps)
+        .sync(false)
+        .callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gstreamer::FlowError::Error)?;
+                    let map = buffer
+                        .map_readable()
+                        .map_err(|_| gstreamer::FlowError::Error)?;
+                    let frame = NetworkFrame {
+                        feed,
+                        data: map.as_slice().to_vec(),
+                        pts: buffer.pts(),
+                    };
+                    send_frame(&frame_sender, (codec, frame))
+                })
+                .build(),
+        )
+        .build();
+    elements.push(sink.upcast());
+
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+    let tee_pad = tee
+        .request_pad_simple("src_%u")
+        .ok_or("Failed to get a pad of the tee")?;
+    let sink_pad = elements[0]
+        .static_pad("sink")
+        .ok_or("Queue has no sink pad")?;
+    tee_pad.link(&sink_pad)?;
+    Ok(Branch { tee_pad, elements })
+}
+
+/// Unlinks `branch` from its tee once no frame is passing through, then takes its elements out
+/// of the pipeline
+fn remove_branch(pipeline: &Pipeline, branch: Branch) {
+    let pipeline = pipeline.clone();
+    let elements = branch.elements;
+    branch
+        .tee_pad
+        .add_probe(gstreamer::PadProbeType::IDLE, move |tee_pad, _| {
+            if let Some(peer) = tee_pad.peer() {
+                let _ = tee_pad.unlink(&peer);
+            }
+            if let Some(tee) = tee_pad.parent_element() {
+                tee.release_request_pad(tee_pad);
+            }
+            // Elements can't be stopped from the thread streaming through them
+            let elements = elements.clone();
+            pipeline.call_async(move |pipeline| {
+                for element in &elements {
+                    let _ = element.set_state(gstreamer::State::Null);
+                }
+                let _ = pipeline.remove_many(&elements);
+            });
+            gstreamer::PadProbeReturn::Remove
+        });
+}
+
+/// What the host's audio is encoded to
+fn opus_caps() -> gstreamer::Caps {
+    gstreamer::Caps::builder("audio/x-opus")
+        .field("rate", AUDIO_RATE)
+        .field("channels", AUDIO_CHANNELS)
+        .field("channel-mapping-family", 0)
+        .build()
+}
+
+/// Adds the elements capturing, mixing and encoding the host's audio to the pipeline. Being in
+/// the same pipeline as the video, its timestamps use the same clock.
+fn build_audio(
+    pipeline: &Pipeline,
+    desktop_audio: bool,
+    microphone: bool,
+    audio_sender: SyncSender<AudioPacket>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mixer = gstreamer::ElementFactory::make("audiomixer").build()?;
+    let caps_filter = gstreamer::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gstreamer::Caps::builder("audio/x-raw")
+                .field("rate", AUDIO_RATE)
+                .field("channels", AUDIO_CHANNELS)
+                .build(),
+        )
+        .build()?;
+    let encoder = gstreamer::ElementFactory::make("opusenc")
+        .property("bitrate", AUDIO_BITRATE)
+        .build()?;
+    let queue = gstreamer::ElementFactory::make("queue").build()?;
+    let sink = AppSink::builder()
+        .caps(&opus_caps())
+        .sync(false)
+        .callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gstreamer::FlowError::Error)?;
+                    let map = buffer
+                        .map_readable()
+                        .map_err(|_| gstreamer::FlowError::Error)?;
+                    let packet = AudioPacket {
+                        data: map.as_slice().to_vec(),
+                        pts: buffer.pts(),
+                    };
+                    // A gap in the audio is better than falling behind
+                    match audio_sender.try_send(packet) {
+                        Err(mpsc::TrySendError::Disconnected(_)) => {
+                            Err(gstreamer::FlowError::Flushing)
+                        }
+                        _ => Ok(gstreamer::FlowSuccess::Ok),
+                    }
+                })
+                .build(),
+        )
+        .build();
+    let elements = [mixer.clone(), caps_filter, encoder, queue, sink.upcast()];
+    pipeline.add_many(&elements)?;
+    gstreamer::Element::link_many(&elements)?;
+
+    // PipeWire offers the monitor of every output through its PulseAudio server
+    let devices = [
+        (desktop_audio, Some("@DEFAULT_MONITOR@")),
+        (microphone, None),
+    ];
+    for (_, device) in devices.into_iter().filter(|(shared, _)| *shared) {
+        let mut source = gstreamer::ElementFactory::make("pulsesrc");
+        if let Some(device) = device {
+            source = source.property("device", device);
+        }
+        let elements = [
+            source.build()?,
+            gstreamer::ElementFactory::make("audioconvert").build()?,
+            gstreamer::ElementFactory::make("audioresample").build()?,
+            mixer.clone(),
+        ];
+        pipeline.add_many(&elements[..3])?;
+        gstreamer::Element::link_many(&elements)?;
+    }
+    Ok(())
+}
+
+/// Drops frames the host loop does not keep up with, blocking the streaming thread would keep
+/// the pipeline from stopping. RTP packets get a longer queue, as every one of them is part of a
+/// frame.
+fn send_frame(
+    frame_sender: &SyncSender<(Codec, NetworkFrame)>,
+    frame: (Codec, NetworkFrame),
+) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+    match frame_sender.try_send(frame) {
+        Err(mpsc::TrySendError::Disconnected(_)) => Err(gstreamer::FlowError::Flushing),
+        _ => Ok(gstreamer::FlowSuccess::Ok),
+    }
+}
+
+/// The clock every pipeline of a client runs on, so audio and video captured at the same time
+/// are played at the same time
+#[derive(Debug)]
+pub struct PlaybackClock {
+    clock: gstreamer::Clock,
+    base_time: gstreamer::ClockTime,
+    /// The host's running time of the first packet and the local running time it arrived at
+    start: Option<(gstreamer::ClockTime, gstreamer::ClockTime)>,
+}
+
+impl PlaybackClock {
+    pub fn new() -> Self {
+        let clock = gstreamer::SystemClock::obtain();
+        let base_time = clock.time();
+        Self {
+            clock,
+            base_time,
+            start: None,
+        }
+    }
+
+    /// Makes `pipeline` play buffers at the local time [`PlaybackClock::local_time`] maps them to
+    fn drive(&self, pipeline: &Pipeline) {
+        pipeline.use_clock(Some(&self.clock));
+        pipeline.set_start_time(gstreamer::ClockTime::NONE);
+        pipeline.set_base_time(self.base_time);
+        // Every pipeline waits the same, whatever latency its elements report
+        pipeline.set_latency(PLAYBACK_DELAY);
+    }
+
+    /// Moves a running time of the host to our running time, keeping the distance to the first
+    /// packet received
+    pub fn local_time(&mut self, host_time: gstreamer::ClockTime) -> gstreamer::ClockTime {
+        let now = self.clock.time().saturating_sub(self.base_time);
+        let (host_start, local_start) = *self.start.get_or_insert((host_time, now));
+        match host_time.checked_sub(host_start) {
+            Some(elapsed) => local_start + elapsed,
+            None => local_start.saturating_sub(host_start - host_time),
+        }
+    }
+}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    pub pipeline: Pipeline,
+    pub source: AppSrc,
+    pub sink: AppSink,
+}
+
+impl Decoder {
+    pub fn new(
+        parameters: &StreamParameters,
+        clock: &PlaybackClock,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        gstreamer::init()?;
+        let pipeline = gstreamer::Pipeline::new();
+        clock.drive(&pipeline);
+
+        let codec = parameters.codec;
+        let source_caps = match parameters.transport {
+            Transport::Quickscreen => codec.caps(),
+            Transport::Rtp => rtp::caps(codec),
+        };
+        // Buffers without the host's capture time are stamped on arrival
+        let source = AppSrc::builder()
+            .caps(&source_caps)
+            .format(gstreamer::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+
+        let mut elements = vec![source.clone().upcast::<gstreamer::Element>()];
+        if parameters.transport == Transport::Rtp {
+            elements.push(gstreamer::ElementFactory::make(codec.depayloader()).build()?);
+        }
+        if let Some(parser) = codec.parser() {
+            elements.push(gstreamer::ElementFactory::make(parser).build()?);
+        }
+        elements.push(codec.build_decoder()?);
+
+        let video_convert = gstreamer::ElementFactory::make("videoconvert").build()?;
+
+        let sink = AppSink::builder()
+            .caps(
+                &gstreamer_video::VideoCapsBuilder::new()
+                    .format(gstreamer_video::VideoFormat::Bgrx)
+                    .build(),
+            )
+            .sync(false)
+            .max_buffers(MAX_QUEUED_FRAMES)
+            .drop(true)
+            .build();
+
+        elements.extend([video_convert, sink.clone().upcast()]);
+
+        pipeline.add_many(&elements)?;
+        gstreamer::Element::link_many(&elements)?;
+
+        pipeline.set_state(gstreamer::State::Playing)?;
 
         Ok(Self {
             pipeline,
+            source,
             sink,
-            frame_index: Mutex::new(0),
         })
     }
+
+    /// Holds frames back until their time on the [`PlaybackClock`] instead of handing them out
+    /// as soon as they are decoded, which keeps them in sync with audio
+    pub fn set_sync(&self, sync: bool) {
+        self.sink.set_sync(sync);
+    }
+
+    /// The frame's timestamp has to be mapped with [`PlaybackClock::local_time`] already
+    pub fn push_frame(&self, frame: NetworkFrame) -> Result<(), gstreamer::FlowError> {
+        let mut buffer = gstreamer::Buffer::from_slice(frame.data);
+        // Keep the host's capture time, so frames can be matched with audio and latency measured
+        if let Some(buffer) = buffer.get_mut() {
+            buffer.set_pts(frame.pts);
+        }
+        self.source.push_buffer(buffer)?;
+        Ok(())
+    }
+
+    /// Waits at most `timeout` for the next decoded frame
+    pub fn pull_frame(&self, timeout: gstreamer::ClockTime) -> Option<DecodedFrame> {
+        let sample = self.sink.try_pull_sample(timeout)?;
+        let video_info = gstreamer_video::VideoInfo::from_caps(sample.caps()?).ok()?;
+        let buffer = sample.buffer()?;
+        let map = buffer.map_readable().ok()?;
+        Some(DecodedFrame {
+            data: map.as_slice().to_vec(),
+            width: video_info.width() as usize,
+            height: video_info.height() as usize,
+            stride: video_info.stride()[0] as usize,
+            pts: buffer.pts(),
+        })
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+    }
+}
+
+/// Plays the host's audio
+#[derive(Debug)]
+pub struct AudioDecoder {
+    pub pipeline: Pipeline,
+    pub source: AppSrc,
+}
+
+impl AudioDecoder {
+    pub fn new(clock: &PlaybackClock) -> Result<Self, Box<dyn std::error::Error>> {
+        gstreamer::init()?;
+        let pipeline = gstreamer::Pipeline::new();
+        clock.drive(&pipeline);
+
+        let source = AppSrc::builder()
+            .caps(&opus_caps())
+            .format(gstreamer::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+        let elements = [
+            source.clone().upcast::<gstreamer::Element>(),
+            gstreamer::ElementFactory::make("opusdec").build()?,
+            gstreamer::ElementFactory::make("audioconvert").build()?,
+            gstreamer::ElementFactory::make("audioresample").build()?,
+            gstreamer::ElementFactory::make("autoaudiosink").build()?,
+        ];
+
+        pipeline.add_many(&elements)?;
+        gstreamer::Element::link_many(&elements)?;
+
+        pipeline.set_state(gstreamer::State::Playing)?;
+
+        Ok(Self { pipeline, source })
+    }
+
+    /// The packet's timestamp has to be mapped with [`PlaybackClock::local_time`] already
+    pub fn push_packet(&self, packet: AudioPacket) -> Result<(), gstreamer::FlowError> {
+        let mut buffer = gstreamer::Buffer::from_slice(packet.data);
+        if let Some(buffer) = buffer.get_mut() {
+            buffer.set_pts(packet.pts);
+        }
+        self.source.push_buffer(buffer)?;
+        Ok(())
+    }
+}
+
+impl Drop for AudioDecoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+    }
 }
@@ -1,9 +1,14 @@
 use std::{
+    collections::HashMap,
     hash::Hash,
     net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
-use crate::encoding::NetworkFrame;
+use crate::encoding::{
+    AudioPacket, CursorBitmap, CursorUpdate, FeedID, NetworkFrame, StreamParameters, Transport,
+    codec::Codec,
+};
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
 pub struct ClientID(pub u16);
@@ -26,25 +31,84 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn send_message(&self, socket: &UdpSocket, message: HostToClientNetworkMessage) {
+    pub fn send_message(&self, socket: &impl LargeSend, message: HostToClientNetworkMessage) {
         let buffer: Vec<u8> = message.into();
-        socket.send_to(&buffer, self.address).unwrap();
+        socket.send_datagram_to(&buffer, self.address).unwrap();
+    }
+}
+
+/// Starts every datagram, so stray packets are not mistaken for messages
+pub const MAGIC: [u8; 2] = *b"QS";
+/// Bumped whenever the wire format changes
+pub const PROTOCOL_VERSION: u8 = 5;
+/// Magic followed by the protocol version
+pub const HEADER_SIZE: usize = 3;
+/// How often both sides tell the other they are still there, even when they have nothing to send
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the other side may stay silent before it is considered gone, a few heartbeats may get
+/// lost on the way
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn with_header(body: Vec<u8>) -> Vec<u8> {
+    let mut output = Vec::with_capacity(HEADER_SIZE + body.len());
+    output.extend_from_slice(&MAGIC);
+    output.push(PROTOCOL_VERSION);
+    output.extend(body);
+    output
+}
+
+/// Checks the magic and protocol version, returning the rest of the datagram
+fn strip_header(value: &[u8]) -> Result<&[u8], NetworkConversionError> {
+    if value.is_empty() {
+        return Err(NetworkConversionError::EmptyBuffer);
+    }
+    if value.len() < HEADER_SIZE || value[..MAGIC.len()] != MAGIC {
+        return Err(NetworkConversionError::UnrecognizedSignature);
+    }
+    let version = value[MAGIC.len()];
+    if version != PROTOCOL_VERSION {
+        return Err(NetworkConversionError::IncompatibleVersion(version));
     }
+    Ok(&value[HEADER_SIZE..])
+}
+
+/// What a client is able to play, sent along with its join request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCapabilities {
+    pub decoders: Vec<Codec>,
+    pub max_resolution: (u16, u16),
 }
 
 #[derive(Debug)]
 pub enum ClientToHostNetworkMessage {
-    JoinRequest(ClientID),
+    /// Sent instead of heartbeats until the host answers, in case it or the answer got lost
+    JoinRequest(ClientID, ClientCapabilities),
     Left(ClientID),
+    /// Sent every [`HEARTBEAT_INTERVAL`] once the host answered, so it knows the client is still
+    /// watching
+    Heartbeat(ClientID),
 }
-pub const CLIENT_TO_HOST_MESSAGE_SIZE: usize = 3;
+/// Large enough for a join request listing every codec
+pub const CLIENT_TO_HOST_MESSAGE_SIZE: usize = 64;
 
 impl From<ClientToHostNetworkMessage> for Vec<u8> {
     fn from(value: ClientToHostNetworkMessage) -> Self {
-        match value {
-            ClientToHostNetworkMessage::JoinRequest(id) => vec![1, id.0 as u8, (id.0 >> 8) as u8],
+        let body = match value {
+            ClientToHostNetworkMessage::JoinRequest(id, capabilities) => {
+                let mut output = vec![1];
+                output.extend_from_slice(&id.0.to_le_bytes());
+                output.extend_from_slice(&capabilities.max_resolution.0.to_le_bytes());
+                output.extend_from_slice(&capabilities.max_resolution.1.to_le_bytes());
+                output.push(capabilities.decoders.len() as u8);
+                output.extend(capabilities.decoders.into_iter().map(u8::from));
+                output
+            }
             ClientToHostNetworkMessage::Left(id) => vec![2, (id.0 as u8), ((id.0 >> 8) as u8)],
-        }
+            ClientToHostNetworkMessage::Heartbeat(id) => {
+                vec![3, (id.0 as u8), ((id.0 >> 8) as u8)]
+            }
+        };
+        with_header(body)
     }
 }
 
@@ -53,144 +117,630 @@ pub enum NetworkConversionError {
     EmptyBuffer,
     UnrecognizedSignature,
     MalformedMessage,
+    /// The other side speaks the contained protocol version
+    IncompatibleVersion(u8),
+    /// A message to send would need more fragments than a [`FragmentHeader`] can count
+    TooManyFragments(usize),
+}
+
+impl std::fmt::Display for NetworkConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyBuffer => write!(f, "received an empty message"),
+            Self::UnrecognizedSignature => write!(f, "received a message of an unknown type"),
+            Self::MalformedMessage => write!(f, "received a malformed message"),
+            Self::IncompatibleVersion(version) => write!(
+                f,
+                "the other side runs {} quickscreen (protocol version {}, ours is {})",
+                newer_or_older(*version),
+                version,
+                PROTOCOL_VERSION
+            ),
+            Self::TooManyFragments(count) => write!(
+                f,
+                "a message needs {} fragments, at most {} fit",
+                count,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NetworkConversionError {}
+
+fn newer_or_older(version: u8) -> &'static str {
+    if version > PROTOCOL_VERSION {
+        "a newer"
+    } else {
+        "an older"
+    }
+}
+
+fn read_u8(value: &[u8], index: usize) -> Result<u8, NetworkConversionError> {
+    value
+        .get(index)
+        .copied()
+        .ok_or(NetworkConversionError::MalformedMessage)
+}
+
+fn read_u16(value: &[u8], index: usize) -> Result<u16, NetworkConversionError> {
+    Ok(u16::from_le_bytes([
+        read_u8(value, index)?,
+        read_u8(value, index + 1)?,
+    ]))
+}
+
+fn read_i32(value: &[u8], index: usize) -> Result<i32, NetworkConversionError> {
+    let bytes = value
+        .get(index..index + 4)
+        .ok_or(NetworkConversionError::MalformedMessage)?;
+    Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(value: &[u8], index: usize) -> Result<u64, NetworkConversionError> {
+    let bytes = value
+        .get(index..index + 8)
+        .ok_or(NetworkConversionError::MalformedMessage)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_codec(value: &[u8], index: usize) -> Result<Codec, NetworkConversionError> {
+    Codec::try_from(read_u8(value, index)?).map_err(|_| NetworkConversionError::MalformedMessage)
+}
+
+/// Size of the stream parameters written by [`write_stream_parameters`]
+const STREAM_PARAMETERS_SIZE: usize = 8;
+
+fn write_stream_parameters(output: &mut Vec<u8>, parameters: &StreamParameters) {
+    output.push(parameters.codec.into());
+    output.push(parameters.transport.into());
+    output.extend_from_slice(&parameters.width.to_le_bytes());
+    output.extend_from_slice(&parameters.height.to_le_bytes());
+    output.extend_from_slice(&parameters.framerate.to_le_bytes());
+}
+
+fn read_stream_parameters(
+    value: &[u8],
+    index: usize,
+) -> Result<StreamParameters, NetworkConversionError> {
+    let transport = Transport::try_from(read_u8(value, index + 1)?)
+        .map_err(|_| NetworkConversionError::MalformedMessage)?;
+    Ok(StreamParameters {
+        codec: read_codec(value, index)?,
+        transport,
+        width: read_u16(value, index + 2)?,
+        height: read_u16(value, index + 4)?,
+        framerate: read_u16(value, index + 6)?,
+    })
+}
+
+fn expect_length(value: &[u8], length: usize) -> Result<(), NetworkConversionError> {
+    if value.len() != length {
+        return Err(NetworkConversionError::MalformedMessage);
+    }
+    Ok(())
 }
 
 impl TryFrom<&[u8]> for ClientToHostNetworkMessage {
     type Error = NetworkConversionError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let value = strip_header(value)?;
         let first_byte = value.first().ok_or(NetworkConversionError::EmptyBuffer)?;
         match first_byte {
             1 => {
-                let id = *value
-                    .get(1)
-                    .ok_or(NetworkConversionError::MalformedMessage)?
-                    as u16
-                    | (*value
-                        .get(2)
-                        .ok_or(NetworkConversionError::MalformedMessage)?
-                        as u16)
-                        << 8;
-                Ok(Self::JoinRequest(ClientID(id)))
+                let id = ClientID(read_u16(value, 1)?);
+                let max_resolution = (read_u16(value, 3)?, read_u16(value, 5)?);
+                let amount_of_decoders = read_u8(value, 7)? as usize;
+                expect_length(value, 8 + amount_of_decoders)?;
+                // Codecs added by newer clients are skipped instead of refusing the whole request
+                let decoders = value[8..]
+                    .iter()
+                    .filter_map(|codec| Codec::try_from(*codec).ok())
+                    .collect();
+                Ok(Self::JoinRequest(
+                    id,
+                    ClientCapabilities {
+                        decoders,
+                        max_resolution,
+                    },
+                ))
             }
             2 => {
-                let id = *value
-                    .get(1)
-                    .ok_or(NetworkConversionError::MalformedMessage)?
-                    as u16
-                    | (*value
-                        .get(2)
-                        .ok_or(NetworkConversionError::MalformedMessage)?
-                        as u16)
-                        << 8;
-                Ok(Self::Left(ClientID(id)))
+                expect_length(value, 3)?;
+                Ok(Self::Left(ClientID(read_u16(value, 1)?)))
+            }
+            3 => {
+                expect_length(value, 3)?;
+                Ok(Self::Heartbeat(ClientID(read_u16(value, 1)?)))
             }
             _ => Err(NetworkConversionError::UnrecognizedSignature),
         }
     }
 }
 
+/// Why the host did not let a client join
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefusalReason {
+    RefusedByHost,
+    UnsupportedCodec(Codec),
+    ResolutionTooLarge(u16, u16),
+    IncompatibleVersion(u8),
+}
+
+impl std::fmt::Display for RefusalReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RefusedByHost => write!(f, "The host refused you"),
+            Self::UnsupportedCodec(codec) => write!(
+                f,
+                "The host streams {}, which this device cannot decode",
+                codec.name()
+            ),
+            Self::ResolutionTooLarge(width, height) => write!(
+                f,
+                "The host streams at {}x{}, which is larger than this device supports",
+                width, height
+            ),
+            Self::IncompatibleVersion(version) => write!(
+                f,
+                "The host runs {} version of quickscreen, both sides need the same version",
+                newer_or_older(*version)
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum HostToClientNetworkMessage {
-    JoinRequestResponse(bool),
+    /// Accepted clients get the parameters of every feed, indexed by [`FeedID`]
+    JoinRequestResponse(Result<Vec<StreamParameters>, RefusalReason>),
     Frame(NetworkFrame),
+    /// A captured source changed size or framerate mid-stream
+    StreamParameters(FeedID, StreamParameters),
+    /// The host's cursor moved or changed its image, only sent when viewers draw it themselves
+    Cursor(CursorUpdate),
+    /// The host's audio, only sent when it shares some
+    Audio(AudioPacket),
+    /// Sent to accepted clients every [`HEARTBEAT_INTERVAL`], so they notice when the host is gone
+    /// even while the screen stands still
+    Heartbeat,
 }
+/// Sent as the timestamp of frames and audio without one
+const NO_PTS: u64 = u64::MAX;
+/// Set in the flags of a cursor message when the cursor is over the captured source
+const CURSOR_VISIBLE: u8 = 1;
+/// Set in the flags of a cursor message carrying a new image
+const CURSOR_BITMAP: u8 = 2;
 pub const HOST_TO_CLIENT_MESSAGE_SIZE: usize = MAX_UDP_SEND_SIZE;
 
 impl From<HostToClientNetworkMessage> for Vec<u8> {
     fn from(value: HostToClientNetworkMessage) -> Self {
-        match value {
-            HostToClientNetworkMessage::JoinRequestResponse(accepted) => vec![1, (accepted as u8)],
+        let body = match value {
+            HostToClientNetworkMessage::JoinRequestResponse(Ok(feeds)) => {
+                let mut output = vec![1, 1, feeds.len() as u8];
+                for parameters in &feeds {
+                    write_stream_parameters(&mut output, parameters);
+                }
+                output
+            }
+            HostToClientNetworkMessage::JoinRequestResponse(Err(reason)) => match reason {
+                RefusalReason::RefusedByHost => vec![1, 0, 1],
+                RefusalReason::UnsupportedCodec(codec) => vec![1, 0, 2, codec.into()],
+                RefusalReason::ResolutionTooLarge(width, height) => {
+                    let mut output = vec![1, 0, 3];
+                    output.extend_from_slice(&width.to_le_bytes());
+                    output.extend_from_slice(&height.to_le_bytes());
+                    output
+                }
+                RefusalReason::IncompatibleVersion(version) => vec![1, 0, 4, version],
+            },
             HostToClientNetworkMessage::Frame(mut frame) => {
-                let mut output = Vec::with_capacity(frame.data.len() + 2);
-                output.push(2);
-                let amount_of_sends = ((frame.data.len() + 2) / MAX_UDP_SEND_SIZE) + 1;
-                output.push(amount_of_sends.try_into().unwrap());
+                let mut output = Vec::with_capacity(frame.data.len() + 10);
+                output.extend([2, frame.feed.0]);
+                let pts = frame.pts.map_or(NO_PTS, |pts| pts.nseconds());
+                output.extend_from_slice(&pts.to_le_bytes());
                 output.append(&mut frame.data);
                 output
             }
-        }
+            HostToClientNetworkMessage::StreamParameters(feed, parameters) => {
+                let mut output = vec![3, feed.0];
+                write_stream_parameters(&mut output, &parameters);
+                output
+            }
+            HostToClientNetworkMessage::Cursor(cursor) => {
+                let mut flags = 0;
+                if cursor.position.is_some() {
+                    flags |= CURSOR_VISIBLE;
+                }
+                if cursor.bitmap.is_some() {
+                    flags |= CURSOR_BITMAP;
+                }
+                let (feed, x, y) = cursor.position.unwrap_or((FeedID(0), 0, 0));
+                let mut output = vec![4, flags, feed.0];
+                output.extend_from_slice(&x.to_le_bytes());
+                output.extend_from_slice(&y.to_le_bytes());
+                if let Some(mut bitmap) = cursor.bitmap {
+                    output.extend_from_slice(&bitmap.width.to_le_bytes());
+                    output.extend_from_slice(&bitmap.height.to_le_bytes());
+                    output.extend_from_slice(&bitmap.hotspot.0.to_le_bytes());
+                    output.extend_from_slice(&bitmap.hotspot.1.to_le_bytes());
+                    output.append(&mut bitmap.data);
+                }
+                output
+            }
+            HostToClientNetworkMessage::Audio(mut packet) => {
+                let mut output = Vec::with_capacity(packet.data.len() + 9);
+                output.push(5);
+                let pts = packet.pts.map_or(NO_PTS, |pts| pts.nseconds());
+                output.extend_from_slice(&pts.to_le_bytes());
+                output.append(&mut packet.data);
+                output
+            }
+            HostToClientNetworkMessage::Heartbeat => vec![6],
+        };
+        with_header(body)
     }
 }
 
 impl TryFrom<&[u8]> for HostToClientNetworkMessage {
     type Error = NetworkConversionError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let value = strip_header(value)?;
         let first_byte = value.first().ok_or(NetworkConversionError::EmptyBuffer)?;
         match first_byte {
             1 => {
-                let accepted = *value
-                    .get(1)
-                    .ok_or(NetworkConversionError::MalformedMessage)?;
-                Ok(Self::JoinRequestResponse(accepted != 0))
+                let accepted = read_u8(value, 1)? != 0;
+                if accepted {
+                    let amount_of_feeds = read_u8(value, 2)? as usize;
+                    expect_length(value, 3 + amount_of_feeds * STREAM_PARAMETERS_SIZE)?;
+                    let feeds = (0..amount_of_feeds)
+                        .map(|feed| {
+                            read_stream_parameters(value, 3 + feed * STREAM_PARAMETERS_SIZE)
+                        })
+                        .collect::<Result<_, _>>()?;
+                    return Ok(Self::JoinRequestResponse(Ok(feeds)));
+                }
+                let (reason, length) = match read_u8(value, 2)? {
+                    1 => (RefusalReason::RefusedByHost, 3),
+                    2 => (RefusalReason::UnsupportedCodec(read_codec(value, 3)?), 4),
+                    3 => (
+                        RefusalReason::ResolutionTooLarge(read_u16(value, 3)?, read_u16(value, 5)?),
+                        7,
+                    ),
+                    4 => (RefusalReason::IncompatibleVersion(read_u8(value, 3)?), 4),
+                    _ => return Err(NetworkConversionError::MalformedMessage),
+                };
+                expect_length(value, length)?;
+                Ok(Self::JoinRequestResponse(Err(reason)))
             }
             2 => {
-                let mut value = value;
-                value.split_off_first();
-                value.split_off_first();
+                let feed = FeedID(read_u8(value, 1)?);
+                let pts = read_u64(value, 2)?;
                 Ok(Self::Frame(NetworkFrame {
-                    data: value.to_vec(),
+                    feed,
+                    data: value[10..].to_vec(),
+                    pts: (pts != NO_PTS).then(|| gstreamer::ClockTime::from_nseconds(pts)),
+                }))
+            }
+            3 => {
+                expect_length(value, 2 + STREAM_PARAMETERS_SIZE)?;
+                Ok(Self::StreamParameters(
+                    FeedID(read_u8(value, 1)?),
+                    read_stream_parameters(value, 2)?,
+                ))
+            }
+            4 => {
+                let flags = read_u8(value, 1)?;
+                let position = (
+                    FeedID(read_u8(value, 2)?),
+                    read_i32(value, 3)?,
+                    read_i32(value, 7)?,
+                );
+                let bitmap = if flags & CURSOR_BITMAP != 0 {
+                    let (width, height) = (read_u16(value, 11)?, read_u16(value, 13)?);
+                    expect_length(value, 19 + width as usize * height as usize * 4)?;
+                    Some(CursorBitmap {
+                        width,
+                        height,
+                        hotspot: (read_u16(value, 15)?, read_u16(value, 17)?),
+                        data: value[19..].to_vec(),
+                    })
+                } else {
+                    expect_length(value, 11)?;
+                    None
+                };
+                Ok(Self::Cursor(CursorUpdate {
+                    position: (flags & CURSOR_VISIBLE != 0).then_some(position),
+                    bitmap,
+                }))
+            }
+            5 => {
+                let pts = read_u64(value, 1)?;
+                Ok(Self::Audio(AudioPacket {
+                    data: value[9..].to_vec(),
+                    pts: (pts != NO_PTS).then(|| gstreamer::ClockTime::from_nseconds(pts)),
                 }))
             }
+            6 => {
+                expect_length(value, 1)?;
+                Ok(Self::Heartbeat)
+            }
             _ => Err(NetworkConversionError::UnrecognizedSignature),
         }
     }
 }
 
-pub trait LargeSend {
-    fn send_to_large(
-        &self,
-        bytes: &[u8],
-        address: SocketAddr,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+/// Marks a datagram as one fragment of a message sent with [`LargeSend::send_to_large`]
+pub const FRAGMENT_SIGNATURE: u8 = 255;
+/// Header, signature, channel, frame sequence (u32), fragment index (u16), fragment count (u16)
+/// and payload length (u16)
+pub const FRAGMENT_HEADER_SIZE: usize = HEADER_SIZE + 12;
+pub const MAX_FRAGMENT_PAYLOAD_SIZE: usize = MAX_UDP_SEND_SIZE - FRAGMENT_HEADER_SIZE;
+/// Keeps datagrams below the 1500 byte Ethernet MTU, leaving room for IP, UDP and tunnel headers
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1200;
+/// How long an incomplete frame is kept around before its fragments are dropped
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(500);
 
-    fn recv_large(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+/// Fragmented messages of different channels are numbered and reassembled independently, so a
+/// completed cursor image does not make the reassembler give up on the frame in progress
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum FragmentChannel {
+    Frame,
+    Cursor,
 }
 
-pub const MAX_UDP_SEND_SIZE: usize = 65507;
-impl LargeSend for UdpSocket {
+impl From<FragmentChannel> for u8 {
+    fn from(value: FragmentChannel) -> Self {
+        match value {
+            FragmentChannel::Frame => 0,
+            FragmentChannel::Cursor => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for FragmentChannel {
+    type Error = NetworkConversionError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Frame),
+            1 => Ok(Self::Cursor),
+            _ => Err(NetworkConversionError::MalformedMessage),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct FragmentHeader {
+    pub channel: FragmentChannel,
+    pub frame_sequence: u32,
+    pub fragment_index: u16,
+    pub fragment_count: u16,
+    pub payload_length: u16,
+}
+
+impl From<FragmentHeader> for [u8; FRAGMENT_HEADER_SIZE] {
+    fn from(value: FragmentHeader) -> Self {
+        let mut output = [0; FRAGMENT_HEADER_SIZE];
+        output[..MAGIC.len()].copy_from_slice(&MAGIC);
+        output[MAGIC.len()] = PROTOCOL_VERSION;
+        let body = &mut output[HEADER_SIZE..];
+        body[0] = FRAGMENT_SIGNATURE;
+        body[1] = value.channel.into();
+        body[2..6].copy_from_slice(&value.frame_sequence.to_le_bytes());
+        body[6..8].copy_from_slice(&value.fragment_index.to_le_bytes());
+        body[8..10].copy_from_slice(&value.fragment_count.to_le_bytes());
+        body[10..12].copy_from_slice(&value.payload_length.to_le_bytes());
+        output
+    }
+}
+
+impl TryFrom<&[u8]> for FragmentHeader {
+    type Error = NetworkConversionError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let body = strip_header(value)?;
+        let first_byte = body.first().ok_or(NetworkConversionError::EmptyBuffer)?;
+        if *first_byte != FRAGMENT_SIGNATURE {
+            return Err(NetworkConversionError::UnrecognizedSignature);
+        }
+        let header = body
+            .get(..FRAGMENT_HEADER_SIZE - HEADER_SIZE)
+            .ok_or(NetworkConversionError::MalformedMessage)?;
+
+        let header = Self {
+            channel: FragmentChannel::try_from(header[1])?,
+            frame_sequence: u32::from_le_bytes([header[2], header[3], header[4], header[5]]),
+            fragment_index: u16::from_le_bytes([header[6], header[7]]),
+            fragment_count: u16::from_le_bytes([header[8], header[9]]),
+            payload_length: u16::from_le_bytes([header[10], header[11]]),
+        };
+        if header.fragment_index >= header.fragment_count
+            || header.payload_length as usize != value.len() - FRAGMENT_HEADER_SIZE
+        {
+            return Err(NetworkConversionError::MalformedMessage);
+        }
+        Ok(header)
+    }
+}
+
+/// Fragments are recognised by their signature even when the protocol version differs
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.starts_with(&MAGIC) && datagram.get(HEADER_SIZE) == Some(&FRAGMENT_SIGNATURE)
+}
+
+/// Splits `bytes` into datagrams of at most `max_payload_size` bytes of payload, each prefixed
+/// with a [`FragmentHeader`]. Fails when that takes more fragments than the header can count.
+pub fn split_into_fragments(
+    bytes: &[u8],
+    channel: FragmentChannel,
+    frame_sequence: u32,
+    max_payload_size: usize,
+) -> Result<Vec<Vec<u8>>, NetworkConversionError> {
+    let max_payload_size = max_payload_size.clamp(1, MAX_FRAGMENT_PAYLOAD_SIZE);
+    let chunks: Vec<&[u8]> = if bytes.is_empty() {
+        vec![bytes]
+    } else {
+        bytes.chunks(max_payload_size).collect()
+    };
+    let fragment_count = u16::try_from(chunks.len())
+        .map_err(|_| NetworkConversionError::TooManyFragments(chunks.len()))?;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(fragment_index, payload)| {
+            let header: [u8; FRAGMENT_HEADER_SIZE] = FragmentHeader {
+                channel,
+                frame_sequence,
+                fragment_index: fragment_index as u16,
+                fragment_count,
+                payload_length: payload.len() as u16,
+            }
+            .into();
+            let mut datagram = Vec::with_capacity(FRAGMENT_HEADER_SIZE + payload.len());
+            datagram.extend_from_slice(&header);
+            datagram.extend_from_slice(payload);
+            datagram
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+struct PartialFrame {
+    fragments: Vec<Option<Vec<u8>>>,
+    received_fragments: usize,
+    first_received: Instant,
+}
+
+/// Collects fragments until every fragment of a frame has arrived
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    partial_frames: HashMap<(FragmentChannel, u32), PartialFrame>,
+    last_completed_frames: HashMap<FragmentChannel, u32>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            partial_frames: HashMap::new(),
+            last_completed_frames: HashMap::new(),
+        }
+    }
+
+    /// Returns the reassembled frame once `datagram` completes it
+    pub fn push(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, NetworkConversionError> {
+        let header = FragmentHeader::try_from(datagram)?;
+        let payload = &datagram[FRAGMENT_HEADER_SIZE..];
+
+        let timeout = self.timeout;
+        self.partial_frames
+            .retain(|_, frame| now.duration_since(frame.first_received) <= timeout);
+
+        // Frames older than the last completed one are useless to the decoder
+        if self
+            .last_completed_frames
+            .get(&header.channel)
+            .is_some_and(|last| !is_newer_frame(header.frame_sequence, *last))
+        {
+            return Ok(None);
+        }
+
+        let key = (header.channel, header.frame_sequence);
+        let frame = self
+            .partial_frames
+            .entry(key)
+            .or_insert_with(|| PartialFrame {
+                fragments: vec![None; header.fragment_count as usize],
+                received_fragments: 0,
+                first_received: now,
+            });
+        let Some(slot) = frame.fragments.get_mut(header.fragment_index as usize) else {
+            return Err(NetworkConversionError::MalformedMessage);
+        };
+        if slot.is_none() {
+            *slot = Some(payload.to_vec());
+            frame.received_fragments += 1;
+        }
+        if frame.received_fragments < frame.fragments.len() {
+            return Ok(None);
+        }
+
+        let frame = self.partial_frames.remove(&key).unwrap();
+        self.last_completed_frames
+            .insert(header.channel, header.frame_sequence);
+        self.partial_frames.retain(|(channel, sequence), _| {
+            *channel != header.channel || is_newer_frame(*sequence, header.frame_sequence)
+        });
+
+        Ok(Some(
+            frame.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+/// Compares frame sequences while allowing them to wrap around
+fn is_newer_frame(sequence: u32, other: u32) -> bool {
+    (sequence.wrapping_sub(other) as i32) > 0
+}
+
+/// Sends and receives messages larger than a datagram, on top of sockets that send and receive
+/// single datagrams
+pub trait LargeSend {
+    fn send_datagram_to(&self, datagram: &[u8], address: SocketAddr) -> std::io::Result<usize>;
+
+    /// Receives from the connected address
+    fn recv_datagram(&self, buffer: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Sends `bytes` as fragments carrying at most `max_payload_size` bytes each
     fn send_to_large(
         &self,
         bytes: &[u8],
+        channel: FragmentChannel,
+        frame_sequence: u32,
+        max_payload_size: usize,
         address: SocketAddr,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let amount_of_slices = bytes.len() / MAX_UDP_SEND_SIZE;
-        dbg!(&amount_of_slices);
-
-        let mut remaining_slice = bytes;
-
-        for _ in 0..amount_of_slices {
-            let (left_slice, right_slice) = remaining_slice.split_at(MAX_UDP_SEND_SIZE - 1);
-            println!("Sending {} bytes of data...", left_slice.len());
-            self.send_to(left_slice, address)?;
-            remaining_slice = right_slice;
+        for fragment in split_into_fragments(bytes, channel, frame_sequence, max_payload_size)? {
+            self.send_datagram_to(&fragment, address)?;
         }
-        self.send_to(remaining_slice, address)?;
-
         Ok(())
     }
-    fn recv_large(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let network_buffer = &mut [0; MAX_UDP_SEND_SIZE];
-        let mut bytes_amount = 0;
-        while bytes_amount == 0 {
-            bytes_amount = self.recv(network_buffer).unwrap_or(0);
-        }
-        let mut remaining_parts = network_buffer[1] - 1;
-        dbg!(remaining_parts);
-
-        let mut output = Vec::with_capacity(MAX_UDP_SEND_SIZE * remaining_parts as usize);
-        output.append(&mut network_buffer.to_vec());
 
-        while remaining_parts > 1 {
-            let bytes_amount = self.recv(network_buffer).unwrap_or(0);
-            if bytes_amount == 0 {
-                continue;
-            }
-            println!("Received {} bytes of data", bytes_amount);
+    /// Receives a single datagram, returning a message once one is complete
+    fn recv_large(
+        &self,
+        reassembler: &mut Reassembler,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let network_buffer = &mut [0; HOST_TO_CLIENT_MESSAGE_SIZE];
+        let bytes_amount = self.recv_datagram(network_buffer)?;
+        let datagram = &network_buffer[..bytes_amount];
 
-            output.append(&mut network_buffer.to_vec());
-            remaining_parts -= 1;
+        if is_fragment(datagram) {
+            Ok(reassembler.push(datagram, Instant::now())?)
+        } else {
+            Ok(Some(datagram.to_vec()))
         }
-        dbg!(output.len() / MAX_UDP_SEND_SIZE);
-        Ok(output)
+    }
+}
+
+pub const MAX_UDP_SEND_SIZE: usize = 65507;
+impl LargeSend for UdpSocket {
+    fn send_datagram_to(&self, datagram: &[u8], address: SocketAddr) -> std::io::Result<usize> {
+        self.send_to(datagram, address)
+    }
+
+    fn recv_datagram(&self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.recv(buffer)
     }
 }
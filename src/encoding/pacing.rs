@@ -0,0 +1,177 @@
+use std::{
+    hash::{DefaultHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Lowest framerate the host can be set to
+pub const MIN_FRAMERATE: u32 = 5;
+/// Highest framerate the host can be set to
+pub const MAX_FRAMERATE: u32 = 60;
+/// Unchanged frames are still sent this often, so new and lossy clients get a picture
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Damage beyond this many regions is merged into a single one
+pub const MAX_DAMAGE_REGIONS: usize = 16;
+/// Places of a plane compared between frames without damage metadata
+const SAMPLES_PER_PLANE: usize = 4096;
+/// Bytes compared at every place, a few pixels
+const SAMPLE_LENGTH: usize = 16;
+
+/// A rectangle of the frame that changed since the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DamageRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DamageRegion {
+    /// The smallest region containing both regions
+    pub fn union(&self, other: &DamageRegion) -> DamageRegion {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width as i32).max(other.x + other.width as i32);
+        let bottom = (self.y + self.height as i32).max(other.y + other.height as i32);
+        DamageRegion {
+            x,
+            y,
+            width: (right - x) as u32,
+            height: (bottom - y) as u32,
+        }
+    }
+
+    /// The part of the region inside a frame of `width` by `height`, `None` when nothing is
+    pub fn clip(&self, width: u32, height: u32) -> Option<DamageRegion> {
+        let clip_axis = |start: i32, length: u32, size: u32| {
+            let end = (start as i64 + length as i64).min(size as i64);
+            let start = (start as i64).max(0);
+            (start < end).then_some((start as i32, (end - start) as u32))
+        };
+        let (x, width) = clip_axis(self.x, self.width, width)?;
+        let (y, height) = clip_axis(self.y, self.height, height)?;
+        Some(DamageRegion {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// Hashes bytes spread evenly over every plane, to notice frames that changed without reading
+/// all of a 4K frame. Changes between the samples go unnoticed until the next refresh.
+pub fn sampled_hash<'a>(planes: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for plane in planes {
+        if plane.len() <= SAMPLES_PER_PLANE * SAMPLE_LENGTH {
+            hasher.write(plane);
+            continue;
+        }
+        // Rows are unlikely to be a multiple of the step, so the samples wander across columns
+        let step = plane.len() / SAMPLES_PER_PLANE;
+        for sample in 0..SAMPLES_PER_PLANE {
+            let start = sample * step;
+            hasher.write(&plane[start..start + SAMPLE_LENGTH]);
+        }
+    }
+    hasher.finish()
+}
+
+/// Decides which captured frames get encoded, limiting them to a target framerate and skipping
+/// frames identical to the last one
+#[derive(Debug)]
+pub struct FramePacer {
+    frame_interval: Duration,
+    /// When the next frame is due
+    next_frame: Option<Instant>,
+    last_sent: Option<Instant>,
+    last_hash: Option<u64>,
+    /// Damage of frames captured since the last sent one
+    pending_damage: Vec<DamageRegion>,
+    /// Damage of the last sent frame, compared to the frame sent before it
+    damage: Vec<DamageRegion>,
+}
+
+impl FramePacer {
+    pub fn new(framerate: u32) -> Self {
+        let framerate = framerate.clamp(MIN_FRAMERATE, MAX_FRAMERATE);
+        Self {
+            frame_interval: Duration::from_secs(1) / framerate,
+            next_frame: None,
+            last_sent: None,
+            last_hash: None,
+            pending_damage: Vec::new(),
+            damage: Vec::new(),
+        }
+    }
+
+    /// Whether a frame captured at `now` should be encoded.
+    ///
+    /// `damage` lists what changed since the previous captured frame, when the compositor tells
+    /// us. Without it the frame is compared to the last sent one using `content_hash`, usually a
+    /// [`sampled_hash`], which is only called for frames that are due.
+    pub fn should_send(
+        &mut self,
+        now: Instant,
+        damage: Option<&[DamageRegion]>,
+        content_hash: impl FnOnce() -> u64,
+    ) -> bool {
+        // Damage of skipped frames still has to reach the next sent one
+        if let Some(damage) = damage {
+            self.add_damage(damage);
+        }
+
+        // Frames arrive with some jitter, a frame slightly early still counts as on time
+        let slack = self.frame_interval / 4;
+        if self
+            .next_frame
+            .is_some_and(|next_frame| now + slack < next_frame)
+        {
+            return false;
+        }
+
+        let hash = match damage {
+            Some(_) => None,
+            None => Some(content_hash()),
+        };
+        let changed = !self.pending_damage.is_empty() || (hash.is_some() && hash != self.last_hash);
+        let refresh_due = self
+            .last_sent
+            .is_none_or(|last_sent| now.duration_since(last_sent) >= REFRESH_INTERVAL);
+        if !changed && !refresh_due {
+            return false;
+        }
+
+        // Keep a steady rhythm, unless we fell behind by more than a frame
+        self.next_frame = Some(match self.next_frame {
+            Some(next_frame) if now < next_frame + self.frame_interval => {
+                next_frame + self.frame_interval
+            }
+            _ => now + self.frame_interval,
+        });
+        self.last_sent = Some(now);
+        self.last_hash = hash;
+        self.damage = std::mem::take(&mut self.pending_damage);
+        true
+    }
+
+    /// Regions that changed in the last sent frame, empty when unknown or when it was sent as a
+    /// refresh
+    pub fn damage(&self) -> &[DamageRegion] {
+        &self.damage
+    }
+
+    fn add_damage(&mut self, damage: &[DamageRegion]) {
+        self.pending_damage.extend_from_slice(damage);
+        if self.pending_damage.len() > MAX_DAMAGE_REGIONS {
+            let bounds = self
+                .pending_damage
+                .iter()
+                .skip(1)
+                .fold(self.pending_damage[0], |bounds, region| {
+                    bounds.union(region)
+                });
+            self.pending_damage = vec![bounds];
+        }
+    }
+}
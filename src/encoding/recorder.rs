@@ -0,0 +1,153 @@
+use crate::encoding::{DEFAULT_BITRATE, DEFAULT_KEYFRAME_INTERVAL, DecodedFrame, codec::Codec};
+use gstreamer::{
+    Pipeline,
+    glib::object::Cast,
+    prelude::{ElementExt, GstBinExtManual},
+};
+use gstreamer_app::AppSrc;
+use std::path::PathBuf;
+
+/// How long [`Recorder::finish`] waits for the file to be written
+const FINISH_TIMEOUT: gstreamer::ClockTime = gstreamer::ClockTime::from_seconds(5);
+
+/// Where a [`Recorder`] puts the frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordTarget {
+    File(PathBuf),
+    /// A video output device, like one made by v4l2loopback
+    VideoDevice(PathBuf),
+}
+
+impl RecordTarget {
+    /// The element putting encoded video in the file, `None` for devices, which get raw frames
+    pub fn muxer(&self) -> Option<&'static str> {
+        let RecordTarget::File(path) = self else {
+            return None;
+        };
+        let is_mp4 = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("mp4"));
+        // Matroska survives being cut off, MP4 is only readable once finished
+        Some(if is_mp4 { "mp4mux" } else { "matroskamux" })
+    }
+}
+
+/// Writes decoded frames of one feed to a [`RecordTarget`]. Frames are scaled to the size of
+/// the first one, as files and devices keep the size they started with.
+#[derive(Debug)]
+pub struct Recorder {
+    pipeline: Pipeline,
+    source: AppSrc,
+    /// Size of the frames pushed last, the source's caps follow it
+    size: (usize, usize),
+}
+
+impl Recorder {
+    pub fn new(
+        target: &RecordTarget,
+        size: (usize, usize),
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        gstreamer::init()?;
+        let pipeline = gstreamer::Pipeline::new();
+
+        // Frames are stamped with the time they arrived
+        let source = AppSrc::builder()
+            .caps(&frame_caps(size))
+            .format(gstreamer::Format::Time)
+            .is_live(true)
+            .do_timestamp(true)
+            .build();
+
+        let (width, height) = size;
+        let mut elements = vec![
+            source.clone().upcast::<gstreamer::Element>(),
+            gstreamer::ElementFactory::make("videoconvert").build()?,
+            gstreamer::ElementFactory::make("videoscale").build()?,
+            gstreamer::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gstreamer::Caps::builder("video/x-raw")
+                        .field("width", width as i32)
+                        .field("height", height as i32)
+                        .field("pixel-aspect-ratio", gstreamer::Fraction::new(1, 1))
+                        .build(),
+                )
+                .build()?,
+        ];
+        match target {
+            RecordTarget::File(path) => {
+                // H.264 plays almost everywhere, any other codec is better than none
+                let codec = [Codec::H264]
+                    .into_iter()
+                    .chain(Codec::available_encoders())
+                    .find(|codec| codec.find_encoder().is_some())
+                    .ok_or("No supported video encoder available")?;
+                elements.push(codec.build_encoder(DEFAULT_BITRATE, DEFAULT_KEYFRAME_INTERVAL)?);
+                if let Some(parser) = codec.parser() {
+                    elements.push(gstreamer::ElementFactory::make(parser).build()?);
+                }
+                elements.push(gstreamer::ElementFactory::make(target.muxer().unwrap()).build()?);
+                elements.push(
+                    gstreamer::ElementFactory::make("filesink")
+                        .property("location", path.to_string_lossy().as_ref())
+                        .build()?,
+                );
+            }
+            RecordTarget::VideoDevice(device) => elements.push(
+                gstreamer::ElementFactory::make("v4l2sink")
+                    .property("device", device.to_string_lossy().as_ref())
+                    .property("sync", false)
+                    .build()?,
+            ),
+        }
+
+        pipeline.add_many(&elements)?;
+        gstreamer::Element::link_many(&elements)?;
+
+        pipeline.set_state(gstreamer::State::Playing)?;
+
+        Ok(Self {
+            pipeline,
+            source,
+            size,
+        })
+    }
+
+    pub fn push_frame(&mut self, frame: DecodedFrame) -> Result<(), gstreamer::FlowError> {
+        let size = (frame.width, frame.height);
+        if size != self.size {
+            self.source.set_caps(Some(&frame_caps(size)));
+            self.size = size;
+        }
+        self.source
+            .push_buffer(gstreamer::Buffer::from_slice(frame.data))?;
+        Ok(())
+    }
+
+    /// Lets the muxer write what it still holds, without which MP4 files are unreadable
+    pub fn finish(self) {
+        let _ = self.source.end_of_stream();
+        if let Some(bus) = self.pipeline.bus() {
+            bus.timed_pop_filtered(
+                FINISH_TIMEOUT,
+                &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+            );
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+    }
+}
+
+/// The BGRx frames a [`super::Decoder`] produces, at a variable framerate
+fn frame_caps((width, height): (usize, usize)) -> gstreamer::Caps {
+    gstreamer_video::VideoCapsBuilder::new()
+        .format(gstreamer_video::VideoFormat::Bgrx)
+        .width(width as i32)
+        .height(height as i32)
+        .framerate(gstreamer::Fraction::new(0, 1))
+        .build()
+}
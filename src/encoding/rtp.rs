@@ -0,0 +1,40 @@
+use crate::encoding::codec::Codec;
+use std::net::SocketAddr;
+
+/// First dynamic payload type, used for the video stream
+pub const PAYLOAD_TYPE: u32 = 96;
+pub const CLOCK_RATE: i32 = 90000;
+/// Size of the RTP packets produced by the payloader, headers included
+pub const MTU: u32 = 1200;
+const RTP_HEADER_SIZE: usize = 12;
+
+/// Checks for an RTP version 2 header, which never collides with quickscreen message signatures
+pub fn is_rtp_packet(bytes: &[u8]) -> bool {
+    bytes.len() >= RTP_HEADER_SIZE && bytes[0] >> 6 == 2
+}
+
+pub fn caps(codec: Codec) -> gstreamer::Caps {
+    gstreamer::Caps::builder("application/x-rtp")
+        .field("media", "video")
+        .field("clock-rate", CLOCK_RATE)
+        .field("encoding-name", codec.rtp_encoding_name())
+        .field("payload", PAYLOAD_TYPE as i32)
+        .build()
+}
+
+/// Describes the stream sent to `destination`, so players like VLC or ffplay can open it
+pub fn session_description(destination: SocketAddr, codec: Codec) -> String {
+    let address_type = if destination.is_ipv4() { "IP4" } else { "IP6" };
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {address_type} {ip}\r\n\
+         s=Quickscreen\r\n\
+         c=IN {address_type} {ip}\r\n\
+         t=0 0\r\n\
+         m=video {port} RTP/AVP {PAYLOAD_TYPE}\r\n\
+         a=rtpmap:{PAYLOAD_TYPE} {encoding_name}/{CLOCK_RATE}\r\n",
+        ip = destination.ip(),
+        port = destination.port(),
+        encoding_name = codec.rtp_encoding_name(),
+    )
+}
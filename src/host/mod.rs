@@ -1,20 +1,52 @@
 use crate::encoding::{
-    Encoder,
+    AudioPacket, CursorUpdate, Encoder, EncoderSettings, FeedID, NetworkFrame, StreamParameters,
+    Transport,
+    codec::Codec,
+    impairment::{ImpairedSocket, ImpairmentProfile},
     network::{
-        CLIENT_TO_HOST_MESSAGE_SIZE, Client, ClientID, ClientToHostNetworkMessage,
-        HostToClientNetworkMessage,
+        CLIENT_TO_HOST_MESSAGE_SIZE, Client, ClientCapabilities, ClientID,
+        ClientToHostNetworkMessage, DEFAULT_MAX_PAYLOAD_SIZE, FragmentChannel, HEARTBEAT_INTERVAL,
+        HEARTBEAT_TIMEOUT, HostToClientNetworkMessage, LargeSend, NetworkConversionError,
+        PROTOCOL_VERSION, RefusalReason,
     },
+    rtp,
 };
 use gstreamer::prelude::{ElementExt, GstObjectExt};
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     sync::mpsc::{Receiver, Sender},
+    time::{Duration, Instant},
 };
 
+const FRAME_PULL_TIMEOUT: gstreamer::ClockTime = gstreamer::ClockTime::from_mseconds(10);
+/// The cursor is sent again this often, so clients that lost a fragment of its image recover
+const CURSOR_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
 pub enum HostingToUIMessage {
+    /// The host is ready for clients on this port, which the system picks when asked for port 0
+    Listening(u16),
     JoinRequest(ClientID),
-    ClientLeft(ClientID),
+    ClientLeft(ClientID, LeaveReason),
+}
+
+/// Why a client stopped watching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// The client said goodbye
+    Left,
+    /// Nothing was heard from the client for [`HEARTBEAT_TIMEOUT`], it crashed, went to sleep or
+    /// lost its connection
+    TimedOut,
+}
+
+impl std::fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Left => write!(f, "left"),
+            Self::TimedOut => write!(f, "stopped answering"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -23,53 +55,170 @@ pub enum UIToHostingMessage {
     JoinRequestResponse(ClientID, bool),
 }
 
+#[derive(Debug, Clone)]
+pub struct HostSettings {
+    pub port: u16,
+    /// Largest amount of frame data put in a single datagram
+    pub max_payload_size: usize,
+    pub encoder: EncoderSettings,
+    /// Emulated bad network everything the host sends goes through
+    pub impairment: ImpairmentProfile,
+    /// Players like VLC or ffplay that get the plain RTP stream without joining, ignored unless
+    /// the transport is RTP
+    pub rtp_destinations: Vec<SocketAddr>,
+}
+
+impl HostSettings {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            encoder: EncoderSettings::default(),
+            impairment: ImpairmentProfile::from_env(),
+            rtp_destinations: Vec::new(),
+        }
+    }
+}
+
 struct HostingState {
-    udp_socket: UdpSocket,
+    settings: HostSettings,
+    /// Indexed by [`FeedID`]
+    stream_parameters: Vec<StreamParameters>,
+    /// Every feed is always encoded with it
+    codec: Codec,
+    /// Codecs clients that can't decode [`HostingState::codec`] may get instead
+    encoders: Vec<Codec>,
+    /// What every pending and accepted client is sent
+    client_codecs: HashMap<ClientID, Codec>,
+    udp_socket: ImpairedSocket,
     pending_clients: HashMap<ClientID, Client>,
     accepted_clients: HashMap<ClientID, Client>,
-    refused_clients: HashMap<ClientID, Client>,
+    /// When each accepted client was last heard from
+    last_heard: HashMap<ClientID, Instant>,
+    last_heartbeat: Instant,
+    /// Told again when their join request arrives again
+    refused_clients: HashMap<ClientID, RefusalReason>,
+    frame_sequence: u32,
+    /// Numbers fragmented cursor updates, apart from frames
+    cursor_sequence: u32,
+    /// Everything the cursor did so far, so clients joining later get its image
+    cursor: Option<CursorUpdate>,
+    last_cursor_refresh: Instant,
 }
 
 pub fn host(
-    port: u16,
+    settings: HostSettings,
     message_sender: Sender<HostingToUIMessage>,
     message_receiver: Receiver<UIToHostingMessage>,
 ) {
-    let encoder = Encoder::new().unwrap();
-    let udp_socket =
-        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).unwrap();
+    let mut encoder = Encoder::new(&settings.encoder).unwrap();
+    let udp_socket = UdpSocket::bind(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        settings.port,
+    ))
+    .unwrap();
+    message_sender
+        .send(HostingToUIMessage::Listening(
+            udp_socket.local_addr().unwrap().port(),
+        ))
+        .unwrap();
+    let udp_socket = ImpairedSocket::new(udp_socket, settings.impairment);
+    // Plain RTP players only know the codec in the session description
+    let encoders = match settings.encoder.transport {
+        Transport::Quickscreen => Codec::available_encoders(),
+        Transport::Rtp => vec![encoder.codec()],
+    };
     let mut state = HostingState {
+        settings,
+        stream_parameters: encoder.stream_parameters(),
+        codec: encoder.codec(),
+        encoders,
+        client_codecs: HashMap::new(),
         udp_socket,
         pending_clients: HashMap::new(),
         accepted_clients: HashMap::new(),
+        last_heard: HashMap::new(),
+        last_heartbeat: Instant::now(),
         refused_clients: HashMap::new(),
+        frame_sequence: 0,
+        cursor_sequence: 0,
+        cursor: None,
+        last_cursor_refresh: Instant::now(),
     };
 
+    if let [stream_parameters] = state.stream_parameters.as_slice()
+        && stream_parameters.transport == Transport::Rtp
+    {
+        for destination in &state.settings.rtp_destinations {
+            println!(
+                "Sending RTP to {}, open this session description to watch:\n{}",
+                destination,
+                rtp::session_description(*destination, stream_parameters.codec)
+            );
+        }
+    }
+
     let client_to_host_buffer = &mut [0; CLIENT_TO_HOST_MESSAGE_SIZE];
     state.udp_socket.set_nonblocking(true).unwrap();
 
+    let bus = encoder.pipeline.bus().unwrap();
+
     loop {
         if let Ok(message) = message_receiver.try_recv() {
             match message {
                 UIToHostingMessage::Stop => break,
                 UIToHostingMessage::JoinRequestResponse(client_id, accepted) => {
-                    handle_join_request_response(client_id, accepted, &mut state)
+                    handle_join_request_response(client_id, accepted, &mut encoder, &mut state)
                 }
             }
         }
 
-        let network_result = state.udp_socket.peek_from(client_to_host_buffer);
-        if let Ok((_, origin)) = network_result {
-            let message_result = client_to_host_buffer.as_slice().try_into();
-            if let Ok(network_message) = message_result {
-                state.udp_socket.recv(&mut []).unwrap();
-                handle_network_message(network_message, origin, &message_sender, &mut state);
+        let network_result = state.udp_socket.recv_from(client_to_host_buffer);
+        if let Ok((amount_of_bytes, origin)) = network_result {
+            match ClientToHostNetworkMessage::try_from(&client_to_host_buffer[..amount_of_bytes]) {
+                Ok(network_message) => {
+                    handle_network_message(network_message, origin, &message_sender, &mut state)
+                }
+                Err(NetworkConversionError::IncompatibleVersion(version)) => {
+                    handle_incompatible_version(version, origin, &state)
+                }
+                Err(_) => {}
             }
         }
 
-        let bus = encoder.pipeline.bus().unwrap();
+        if state.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            send_heartbeats(&mut state);
+        }
+        time_out_clients(&message_sender, &mut state);
+        let needed_codecs: Vec<Codec> = state
+            .accepted_clients
+            .keys()
+            .filter_map(|client_id| state.client_codecs.get(client_id).copied())
+            .collect();
+        encoder.keep_encodings(&needed_codecs);
+
+        let mut timeout = FRAME_PULL_TIMEOUT;
+        while let Some((codec, frame)) = encoder.pull_frame(timeout) {
+            send_frame(codec, frame, &mut state);
+            timeout = gstreamer::ClockTime::ZERO;
+        }
 
-        for msg in bus.iter_timed(gstreamer::ClockTime::NONE) {
+        while let Some(packet) = encoder.pull_audio() {
+            send_audio(packet, &state);
+        }
+
+        if let Some(cursor) = encoder.pull_cursor() {
+            send_cursor(cursor, &mut state);
+        }
+        if state.last_cursor_refresh.elapsed() >= CURSOR_REFRESH_INTERVAL {
+            refresh_cursor(&mut state);
+        }
+
+        for (feed, stream_parameters) in encoder.update_stream_parameters() {
+            handle_stream_parameters_changed(feed, stream_parameters, &mut state);
+        }
+
+        for msg in bus.iter() {
             use gstreamer::MessageView;
 
             match msg.view() {
@@ -91,6 +240,165 @@ pub fn host(
     println!("Stopped hosting");
 }
 
+/// Sends `frame` to the clients it was encoded for
+fn send_frame(codec: Codec, frame: NetworkFrame, state: &mut HostingState) {
+    let clients: Vec<&Client> = state
+        .accepted_clients
+        .values()
+        .filter(|client| state.client_codecs.get(&client.id) == Some(&codec))
+        .collect();
+
+    if state.settings.encoder.transport == Transport::Rtp {
+        for client in clients {
+            if let Err(error) = state.udp_socket.send_to(&frame.data, client.address) {
+                eprintln!("Failed to send packet to client {}: {}", client.id.0, error);
+            }
+        }
+        for destination in &state.settings.rtp_destinations {
+            if let Err(error) = state.udp_socket.send_to(&frame.data, *destination) {
+                eprintln!("Failed to send packet to {}: {}", destination, error);
+            }
+        }
+        return;
+    }
+
+    if clients.is_empty() {
+        return;
+    }
+
+    let buffer: Vec<u8> = HostToClientNetworkMessage::Frame(frame).into();
+    state.frame_sequence = state.frame_sequence.wrapping_add(1);
+    for client in clients {
+        if let Err(error) = state.udp_socket.send_to_large(
+            &buffer,
+            FragmentChannel::Frame,
+            state.frame_sequence,
+            state.settings.max_payload_size,
+            client.address,
+        ) {
+            eprintln!("Failed to send frame to client {}: {}", client.id.0, error);
+        }
+    }
+}
+
+/// Opus packets are small enough to always fit a single datagram
+fn send_audio(packet: AudioPacket, state: &HostingState) {
+    if state.accepted_clients.is_empty() {
+        return;
+    }
+
+    let buffer: Vec<u8> = HostToClientNetworkMessage::Audio(packet).into();
+    for client in state.accepted_clients.values() {
+        if let Err(error) = state.udp_socket.send_to(&buffer, client.address) {
+            eprintln!("Failed to send audio to client {}: {}", client.id.0, error);
+        }
+    }
+}
+
+fn send_heartbeats(state: &mut HostingState) {
+    state.last_heartbeat = Instant::now();
+    let buffer: Vec<u8> = HostToClientNetworkMessage::Heartbeat.into();
+    for client in state.accepted_clients.values() {
+        if let Err(error) = state.udp_socket.send_to(&buffer, client.address) {
+            eprintln!(
+                "Failed to send heartbeat to client {}: {}",
+                client.id.0, error
+            );
+        }
+    }
+}
+
+/// Stops sending to clients that went silent without saying goodbye
+fn time_out_clients(message_sender: &Sender<HostingToUIMessage>, state: &mut HostingState) {
+    let silent_clients: Vec<ClientID> = state
+        .last_heard
+        .iter()
+        .filter(|(_, last_heard)| last_heard.elapsed() > HEARTBEAT_TIMEOUT)
+        .map(|(client_id, _)| *client_id)
+        .collect();
+    for client_id in silent_clients {
+        handle_client_left(client_id, LeaveReason::TimedOut, message_sender, state);
+    }
+}
+
+fn send_cursor(cursor: CursorUpdate, state: &mut HostingState) {
+    state.cursor = Some(match state.cursor.take() {
+        Some(last) => last.then(cursor.clone()),
+        None => cursor.clone(),
+    });
+    let clients: Vec<Client> = state.accepted_clients.values().cloned().collect();
+    send_cursor_to(cursor, &clients, state);
+}
+
+/// Sends everything the cursor did so far again, image included
+fn refresh_cursor(state: &mut HostingState) {
+    state.last_cursor_refresh = Instant::now();
+    if let Some(cursor) = state.cursor.clone() {
+        let clients: Vec<Client> = state.accepted_clients.values().cloned().collect();
+        send_cursor_to(cursor, &clients, state);
+    }
+}
+
+/// Sends a cursor update in a single datagram, only new cursor images have to be split up
+fn send_cursor_to(cursor: CursorUpdate, clients: &[Client], state: &mut HostingState) {
+    if clients.is_empty() {
+        return;
+    }
+
+    let buffer: Vec<u8> = HostToClientNetworkMessage::Cursor(cursor).into();
+    let fragmented = buffer.len() > state.settings.max_payload_size;
+    if fragmented {
+        state.cursor_sequence = state.cursor_sequence.wrapping_add(1);
+    }
+    for client in clients {
+        let result = if fragmented {
+            state.udp_socket.send_to_large(
+                &buffer,
+                FragmentChannel::Cursor,
+                state.cursor_sequence,
+                state.settings.max_payload_size,
+                client.address,
+            )
+        } else {
+            state
+                .udp_socket
+                .send_to(&buffer, client.address)
+                .map(|_| ())
+                .map_err(Into::into)
+        };
+        if let Err(error) = result {
+            eprintln!("Failed to send cursor to client {}: {}", client.id.0, error);
+        }
+    }
+}
+
+fn handle_stream_parameters_changed(
+    feed: FeedID,
+    stream_parameters: StreamParameters,
+    state: &mut HostingState,
+) {
+    println!(
+        "Now streaming feed {} at {}x{} and {} fps",
+        feed.0, stream_parameters.width, stream_parameters.height, stream_parameters.framerate
+    );
+    state.stream_parameters[feed.0 as usize] = stream_parameters;
+    for client in state.accepted_clients.values() {
+        let Some(codec) = state.client_codecs.get(&client.id) else {
+            continue;
+        };
+        client.send_message(
+            &state.udp_socket,
+            HostToClientNetworkMessage::StreamParameters(
+                feed,
+                StreamParameters {
+                    codec: *codec,
+                    ..stream_parameters
+                },
+            ),
+        );
+    }
+}
+
 fn handle_network_message(
     message: ClientToHostNetworkMessage,
     origin: SocketAddr,
@@ -98,57 +406,194 @@ fn handle_network_message(
     state: &mut HostingState,
 ) {
     match message {
-        ClientToHostNetworkMessage::JoinRequest(client_id) => {
-            handle_join_request(client_id, origin, ui_sender, state)
+        ClientToHostNetworkMessage::JoinRequest(client_id, capabilities) => {
+            handle_join_request(client_id, capabilities, origin, ui_sender, state)
         }
         ClientToHostNetworkMessage::Left(client_id) => {
-            handle_client_left(client_id, ui_sender, state)
+            handle_client_left(client_id, LeaveReason::Left, ui_sender, state)
+        }
+        // Pending clients send them too, they only count once accepted
+        ClientToHostNetworkMessage::Heartbeat(client_id) => {
+            if let Some(last_heard) = state.last_heard.get_mut(&client_id) {
+                *last_heard = Instant::now();
+            }
         }
     }
 }
 
 fn handle_join_request(
     client_id: ClientID,
+    capabilities: ClientCapabilities,
     client_address: SocketAddr,
     ui_sender: &Sender<HostingToUIMessage>,
     state: &mut HostingState,
 ) {
-    if state.refused_clients.contains_key(&client_id) {
+    let client = client_id.as_client(client_address);
+    // Clients repeat their request until they get an answer, which may have been lost
+    if state.pending_clients.contains_key(&client_id) {
+        return;
+    }
+    if let Some(&reason) = state.refused_clients.get(&client_id) {
+        client.send_message(
+            &state.udp_socket,
+            HostToClientNetworkMessage::JoinRequestResponse(Err(reason)),
+        );
+        return;
+    }
+    if state.accepted_clients.contains_key(&client_id)
+        && let Some(&codec) = state.client_codecs.get(&client_id)
+    {
+        state.last_heard.insert(client_id, Instant::now());
+        client.send_message(
+            &state.udp_socket,
+            HostToClientNetworkMessage::JoinRequestResponse(Ok(stream_parameters_for(
+                codec, state,
+            ))),
+        );
         return;
     }
 
-    state
-        .pending_clients
-        .insert(client_id, client_id.as_client(client_address));
+    let codec = match negotiate_codec(&capabilities, state) {
+        Ok(codec) => codec,
+        Err(reason) => {
+            println!("Client {} can't join: {}", client_id.0, reason);
+            client.send_message(
+                &state.udp_socket,
+                HostToClientNetworkMessage::JoinRequestResponse(Err(reason)),
+            );
+            return;
+        }
+    };
+
+    state.client_codecs.insert(client_id, codec);
+    state.pending_clients.insert(client_id, client);
 
     ui_sender
         .send(HostingToUIMessage::JoinRequest(client_id))
         .unwrap();
 }
 
-fn handle_join_request_response(client_id: ClientID, accepted: bool, state: &mut HostingState) {
-    let client = state.pending_clients.get(&client_id).unwrap().clone();
+/// Tells the client which version we speak, the header alone is enough for it to explain the
+/// refusal
+fn handle_incompatible_version(version: u8, origin: SocketAddr, state: &HostingState) {
+    println!(
+        "Refusing {}, it speaks protocol version {} instead of {}",
+        origin, version, PROTOCOL_VERSION
+    );
+    let buffer: Vec<u8> = HostToClientNetworkMessage::JoinRequestResponse(Err(
+        RefusalReason::IncompatibleVersion(PROTOCOL_VERSION),
+    ))
+    .into();
+    if let Err(error) = state.udp_socket.send_to(&buffer, origin) {
+        eprintln!("Failed to refuse {}: {}", origin, error);
+    }
+}
+
+/// The codec the client gets, the host's own when the client can decode it
+fn negotiate_codec(
+    capabilities: &ClientCapabilities,
+    state: &HostingState,
+) -> Result<Codec, RefusalReason> {
+    let codec = Codec::negotiate(state.codec, &state.encoders, &capabilities.decoders)
+        .ok_or(RefusalReason::UnsupportedCodec(state.codec))?;
+    check_capabilities(capabilities, &stream_parameters_for(codec, state))?;
+    Ok(codec)
+}
+
+/// How every feed is sent to clients getting `codec`
+fn stream_parameters_for(codec: Codec, state: &HostingState) -> Vec<StreamParameters> {
+    state
+        .stream_parameters
+        .iter()
+        .map(|stream_parameters| StreamParameters {
+            codec,
+            ..*stream_parameters
+        })
+        .collect()
+}
+
+/// Every feed has to be playable by the client
+fn check_capabilities(
+    capabilities: &ClientCapabilities,
+    feeds: &[StreamParameters],
+) -> Result<(), RefusalReason> {
+    for stream_parameters in feeds {
+        if !capabilities.decoders.contains(&stream_parameters.codec) {
+            return Err(RefusalReason::UnsupportedCodec(stream_parameters.codec));
+        }
+        let (max_width, max_height) = capabilities.max_resolution;
+        if stream_parameters.width > max_width || stream_parameters.height > max_height {
+            return Err(RefusalReason::ResolutionTooLarge(
+                stream_parameters.width,
+                stream_parameters.height,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn handle_join_request_response(
+    client_id: ClientID,
+    mut accepted: bool,
+    encoder: &mut Encoder,
+    state: &mut HostingState,
+) {
+    // The client may have left while the host was deciding
+    let Some(client) = state.pending_clients.remove(&client_id) else {
+        return;
+    };
+    let Some(&codec) = state.client_codecs.get(&client_id) else {
+        return;
+    };
+    let mut refusal = RefusalReason::RefusedByHost;
+    if accepted && let Err(error) = encoder.encode_with(codec) {
+        eprintln!("Failed to encode with {}: {}", codec.name(), error);
+        accepted = false;
+        refusal = RefusalReason::UnsupportedCodec(codec);
+    }
     if accepted {
-        println!("Client {} accepted", &client_id.0);
+        println!("Client {} accepted, sending {}", &client_id.0, codec.name());
+        if let [stream_parameters] = state.stream_parameters.as_slice()
+            && stream_parameters.transport == Transport::Rtp
+        {
+            println!(
+                "Session description for client {}:\n{}",
+                client_id.0,
+                rtp::session_description(client.address, codec)
+            );
+        }
         state.accepted_clients.insert(client_id, client.clone());
+        state.last_heard.insert(client_id, Instant::now());
     } else {
         println!("Client {} refused", &client_id.0);
-        state.refused_clients.insert(client_id, client.clone());
+        state.refused_clients.insert(client_id, refusal);
+        state.client_codecs.remove(&client_id);
     }
     client.send_message(
         &state.udp_socket,
-        HostToClientNetworkMessage::JoinRequestResponse(accepted),
+        HostToClientNetworkMessage::JoinRequestResponse(if accepted {
+            Ok(stream_parameters_for(codec, state))
+        } else {
+            Err(refusal)
+        }),
     );
+    if accepted && let Some(cursor) = state.cursor.clone() {
+        send_cursor_to(cursor, &[client], state);
+    }
 }
 
 fn handle_client_left(
     client_id: ClientID,
+    reason: LeaveReason,
     message_sender: &Sender<HostingToUIMessage>,
     state: &mut HostingState,
 ) {
-    println!("Client {} left", client_id.0);
+    println!("Client {} {}", client_id.0, reason);
+    state.pending_clients.remove(&client_id);
     state.accepted_clients.remove(&client_id);
+    state.last_heard.remove(&client_id);
+    state.client_codecs.remove(&client_id);
     message_sender
-        .send(HostingToUIMessage::ClientLeft(client_id))
+        .send(HostingToUIMessage::ClientLeft(client_id, reason))
         .unwrap()
 }
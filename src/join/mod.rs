@@ -1,45 +1,83 @@
-use crate::{
-    encoding::RESOLUTION,
-    encoding::{
-        NetworkFrame,
-        network::{
-            ClientID, ClientToHostNetworkMessage, HOST_TO_CLIENT_MESSAGE_SIZE,
-            HostToClientNetworkMessage, LargeSend, MAX_UDP_SEND_SIZE,
-        },
+use crate::encoding::{
+    AudioDecoder, AudioPacket, CursorBitmap, CursorUpdate, DecodedFrame, Decoder, FeedID,
+    NetworkFrame, PlaybackClock, StreamParameters,
+    codec::Codec,
+    impairment::{ImpairedSocket, ImpairmentProfile},
+    network::{
+        ClientCapabilities, ClientID, ClientToHostNetworkMessage, HEARTBEAT_INTERVAL,
+        HEARTBEAT_TIMEOUT, HostToClientNetworkMessage, LargeSend, NetworkConversionError,
+        Reassembler, RefusalReason,
     },
+    rtp,
 };
-use libadwaita::gtk::cairo::{Format, ImageSurface, Surface};
+use libadwaita::gtk::cairo::{Format, ImageSurface};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     sync::mpsc::{Receiver, Sender},
+    time::Instant,
 };
 
+const FRAME_PULL_TIMEOUT: gstreamer::ClockTime = gstreamer::ClockTime::ZERO;
+
+/// Largest stream we are willing to decode
+const MAX_RESOLUTION: (u16, u16) = (7680, 4320);
+
 #[derive(Debug)]
 pub enum JoinedToUIMessage {
-    JoinRequestResponse(bool),
-    Frame(NetworkFrame),
+    /// The parameters of every feed when accepted, indexed by [`FeedID`]
+    JoinRequestResponse(Result<Vec<StreamParameters>, RefusalReason>),
+    Frame(FeedID, DecodedFrame),
+    StreamParameters(FeedID, StreamParameters),
+    Cursor(CursorUpdate),
+    /// Nothing was heard from the host for [`HEARTBEAT_TIMEOUT`] after joining, it stopped
+    /// hosting or lost its connection. Joining ends without being told to leave.
+    HostLost,
 }
 pub enum UIToJoinedMessage {
     Leave,
 }
 
+/// Everything playing the host's stream
+#[derive(Debug, Default)]
+struct Playback {
+    clock: PlaybackClock,
+    /// One for every feed, created once the host accepted us and told us how they are encoded
+    decoders: Vec<Decoder>,
+    /// Created when the first audio arrives, as not every host shares audio
+    audio: Option<AudioDecoder>,
+    /// Whether the host answered the join request, later answers repeat the first one
+    answered: bool,
+}
+
 pub fn join(
     address: IpAddr,
     port: u16,
+    impairment: ImpairmentProfile,
     message_sender: Sender<JoinedToUIMessage>,
     message_receiver: Receiver<UIToJoinedMessage>,
 ) {
-    let udp_socket =
-        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port + 1)).unwrap();
+    let udp_socket = ImpairedSocket::new(
+        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap(),
+        impairment,
+    );
     udp_socket.connect(SocketAddr::new(address, port)).unwrap();
 
+    gstreamer::init().unwrap();
+    let capabilities = ClientCapabilities {
+        decoders: Codec::available_decoders(),
+        max_resolution: MAX_RESOLUTION,
+    };
+
     let id = ClientID::generate();
-    let network_buffer: Vec<u8> = ClientToHostNetworkMessage::JoinRequest(id).into();
-    udp_socket.send(&network_buffer).unwrap();
+    let join_request: Vec<u8> = ClientToHostNetworkMessage::JoinRequest(id, capabilities).into();
+    udp_socket.send(&join_request).unwrap();
 
     udp_socket.set_nonblocking(true).unwrap();
 
-    let network_buffer = &mut [0; HOST_TO_CLIENT_MESSAGE_SIZE];
+    let mut reassembler = Reassembler::default();
+    let mut playback = Playback::default();
+    let mut last_heard = Instant::now();
+    let mut last_heartbeat = Instant::now();
     loop {
         if let Ok(message) = message_receiver.try_recv() {
             match message {
@@ -47,26 +85,64 @@ pub fn join(
             }
         }
 
-        let network_result = udp_socket.peek(network_buffer);
-        if let Ok(amount_of_bytes) = network_result {
-            if amount_of_bytes >= MAX_UDP_SEND_SIZE - 1 {
-                println!("Receiving frame...");
-                let message = udp_socket
-                    .recv_large()
-                    .unwrap()
-                    .as_slice()
-                    .try_into()
-                    .unwrap();
-                handle_network_message(message, &message_sender);
+        if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            last_heartbeat = Instant::now();
+            // Join requests get lost too, so they take the place of heartbeats until answered
+            let network_buffer: Vec<u8> = match playback.answered {
+                true => ClientToHostNetworkMessage::Heartbeat(id).into(),
+                false => join_request.clone(),
+            };
+            // Fails while the host is unreachable, which the timeout below notices
+            let _ = udp_socket.send(&network_buffer);
+        }
+
+        // The host only has to answer once it accepted us, a human may take a while to decide
+        let joined = !playback.decoders.is_empty();
+        if joined && last_heard.elapsed() > HEARTBEAT_TIMEOUT {
+            println!("The host stopped answering");
+            message_sender.send(JoinedToUIMessage::HostLost).unwrap();
+            return;
+        }
+
+        let received = udp_socket.recv_large(&mut reassembler);
+        if received.is_ok() {
+            last_heard = Instant::now();
+        }
+        if let Ok(Some(bytes)) = received {
+            if rtp::is_rtp_packet(&bytes) {
+                // The depayloader takes the timing from the RTP headers, RTP only carries one feed
+                handle_frame(
+                    NetworkFrame {
+                        feed: FeedID(0),
+                        data: bytes,
+                        pts: None,
+                    },
+                    &mut playback,
+                );
             } else {
-                println!("Receiving something else...");
-                let message_result = network_buffer.as_slice().try_into();
-                if let Ok(network_message) = message_result {
-                    udp_socket.recv(&mut []).unwrap();
-                    handle_network_message(network_message, &message_sender);
+                match HostToClientNetworkMessage::try_from(bytes.as_slice()) {
+                    Ok(network_message) => {
+                        handle_network_message(network_message, &mut playback, &message_sender)
+                    }
+                    Err(NetworkConversionError::IncompatibleVersion(version)) => {
+                        handle_join_request_response(
+                            Err(RefusalReason::IncompatibleVersion(version)),
+                            &mut playback,
+                            &message_sender,
+                        )
+                    }
+                    Err(error) => eprintln!("Ignoring message from host: {}", error),
                 }
             }
         }
+
+        for (feed, decoder) in playback.decoders.iter().enumerate() {
+            while let Some(frame) = decoder.pull_frame(FRAME_PULL_TIMEOUT) {
+                message_sender
+                    .send(JoinedToUIMessage::Frame(FeedID(feed as u8), frame))
+                    .unwrap();
+            }
+        }
     }
     println!("Leaving...");
 
@@ -76,57 +152,128 @@ pub fn join(
 
 fn handle_network_message(
     message: HostToClientNetworkMessage,
+    playback: &mut Playback,
     message_sender: &Sender<JoinedToUIMessage>,
 ) {
     match message {
-        HostToClientNetworkMessage::JoinRequestResponse(accepted) => {
-            handle_join_request_response(accepted, message_sender)
+        HostToClientNetworkMessage::JoinRequestResponse(response) => {
+            handle_join_request_response(response, playback, message_sender)
         }
-        HostToClientNetworkMessage::Frame(frame) => handle_frame(frame, message_sender),
+        HostToClientNetworkMessage::Frame(frame) => handle_frame(frame, playback),
+        // The decoder picks the new size up from the stream itself, only the UI needs to know
+        HostToClientNetworkMessage::StreamParameters(feed, stream_parameters) => message_sender
+            .send(JoinedToUIMessage::StreamParameters(feed, stream_parameters))
+            .unwrap(),
+        HostToClientNetworkMessage::Cursor(cursor) => message_sender
+            .send(JoinedToUIMessage::Cursor(cursor))
+            .unwrap(),
+        HostToClientNetworkMessage::Audio(packet) => handle_audio(packet, playback),
+        // Receiving it was all it was for
+        HostToClientNetworkMessage::Heartbeat => {}
     }
 }
 
-fn handle_join_request_response(accepted: bool, message_sender: &Sender<JoinedToUIMessage>) {
-    if accepted {
-        println!("We were accepted")
-    } else {
-        println!("We were refused")
+fn handle_join_request_response(
+    response: Result<Vec<StreamParameters>, RefusalReason>,
+    playback: &mut Playback,
+    message_sender: &Sender<JoinedToUIMessage>,
+) {
+    if playback.answered {
+        return;
+    }
+    playback.answered = true;
+    let response = response.and_then(|feeds| {
+        println!("We were accepted");
+        playback.decoders = feeds
+            .iter()
+            .map(|stream_parameters| {
+                Decoder::new(stream_parameters, &playback.clock).map_err(|error| {
+                    eprintln!("Failed to create decoder: {}", error);
+                    RefusalReason::UnsupportedCodec(stream_parameters.codec)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(feeds)
+    });
+    if let Err(reason) = &response {
+        println!("We were refused: {}", reason);
     }
     message_sender
-        .send(JoinedToUIMessage::JoinRequestResponse(accepted))
+        .send(JoinedToUIMessage::JoinRequestResponse(response))
         .unwrap();
 }
 
-fn handle_frame(frame: NetworkFrame, message_sender: &Sender<JoinedToUIMessage>) {
-    message_sender
-        .send(JoinedToUIMessage::Frame(frame))
-        .unwrap()
+fn handle_frame(mut frame: NetworkFrame, playback: &mut Playback) {
+    let Some(decoder) = playback.decoders.get(frame.feed.0 as usize) else {
+        return;
+    };
+    frame.pts = frame.pts.map(|pts| playback.clock.local_time(pts));
+    if let Err(error) = decoder.push_frame(frame) {
+        eprintln!("Failed to decode frame: {:?}", error);
+    }
 }
 
-impl From<NetworkFrame> for Surface {
-    fn from(value: NetworkFrame) -> Self {
-        println!("Converting NetworkFrame to Surface...");
-        let mut rgbx_data = Vec::with_capacity(RESOLUTION.0 * RESOLUTION.1 * 4);
-        for i in 0..value.data.len() {
-            rgbx_data.push(value.data[i]);
-            if (i + 1) % 3 == 0 {
-                rgbx_data.push(0);
+/// Frames are shown as soon as they are decoded until the host turns out to share audio, from
+/// then on they wait for the audio captured with them
+fn handle_audio(mut packet: AudioPacket, playback: &mut Playback) {
+    if playback.audio.is_none() {
+        match AudioDecoder::new(&playback.clock) {
+            Ok(audio) => {
+                for decoder in &playback.decoders {
+                    decoder.set_sync(true);
+                }
+                playback.audio = Some(audio);
+            }
+            Err(error) => {
+                eprintln!("Failed to play audio: {}", error);
+                return;
             }
         }
-        dbg!(rgbx_data.len());
+    }
+    packet.pts = packet.pts.map(|pts| playback.clock.local_time(pts));
+    if let Some(audio) = &playback.audio
+        && let Err(error) = audio.push_packet(packet)
+    {
+        eprintln!("Failed to decode audio: {:?}", error);
+    }
+}
 
+impl From<DecodedFrame> for ImageSurface {
+    fn from(value: DecodedFrame) -> Self {
+        // Cairo's Rgb24 is native-endian xRGB, which matches BGRx on little-endian machines
         ImageSurface::create_for_data(
-            rgbx_data,
-            libadwaita::gtk::cairo::Format::Rgb24,
-            RESOLUTION.0 as i32,
-            RESOLUTION.1 as i32,
-            Format::Rgb24.stride_for_width(RESOLUTION.0 as u32).unwrap(),
+            value.data,
+            Format::Rgb24,
+            value.width as i32,
+            value.height as i32,
+            value.stride as i32,
         )
         .unwrap()
-        .create_similar(
-            libadwaita::gtk::cairo::Content::Color,
-            RESOLUTION.0 as i32,
-            RESOLUTION.1 as i32,
+    }
+}
+
+impl From<CursorBitmap> for ImageSurface {
+    fn from(value: CursorBitmap) -> Self {
+        // Cairo's Argb32 is native-endian with premultiplied alpha, so BGRA on little-endian
+        let data: Vec<u8> = value
+            .data
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let premultiply = |channel: u8| (channel as u16 * pixel[3] as u16 / 255) as u8;
+                [
+                    premultiply(pixel[2]),
+                    premultiply(pixel[1]),
+                    premultiply(pixel[0]),
+                    pixel[3],
+                ]
+            })
+            .collect();
+        ImageSurface::create_for_data(
+            data,
+            Format::ARgb32,
+            value.width as i32,
+            value.height as i32,
+            value.width as i32 * 4,
         )
         .unwrap()
     }
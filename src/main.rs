@@ -4,6 +4,7 @@ use libadwaita::{
     gtk::prelude::GtkWindowExt,
 };
 
+mod cli;
 pub mod encoding;
 mod host;
 mod join;
@@ -12,6 +13,12 @@ mod tests;
 mod ui;
 
 fn main() {
+    // Without a command the window opens
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     let application = Application::builder()
         .application_id("com.zendard.quickscreen")
         .build();
@@ -1,9 +1,1203 @@
-use crate::host;
-use std::sync::mpsc;
+use crate::encoding::{
+    AudioPacket, CursorBitmap, CursorMode, CursorUpdate, FeedID, NetworkFrame, SourceKind,
+    StreamParameters, Transport,
+    capture::CaptureSource,
+    codec::Codec,
+    impairment::{ImpairedSocket, ImpairmentProfile},
+    layout::{CropRegion, Layout},
+    network::{
+        CLIENT_TO_HOST_MESSAGE_SIZE, ClientCapabilities, ClientID, ClientToHostNetworkMessage,
+        DEFAULT_MAX_PAYLOAD_SIZE, FRAGMENT_HEADER_SIZE, FragmentChannel,
+        HOST_TO_CLIENT_MESSAGE_SIZE, HostToClientNetworkMessage, NetworkConversionError,
+        PROTOCOL_VERSION, Reassembler, RefusalReason, is_fragment, split_into_fragments,
+    },
+    pacing::{DamageRegion, FramePacer, MAX_DAMAGE_REGIONS, REFRESH_INTERVAL, sampled_hash},
+    recorder::RecordTarget,
+    rtp,
+};
+use crate::{
+    cli, host,
+    join::{self, JoinedToUIMessage, UIToJoinedMessage},
+};
+use rand::seq::SliceRandom;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 #[test]
 fn host() {
-    let (sender, _) = mpsc::channel();
-    let (_, receiver) = mpsc::channel();
-    host::host(1111, sender, receiver);
+    let (sender, _receiver) = mpsc::channel();
+    let (stop_sender, receiver) = mpsc::channel();
+    let mut settings = host::HostSettings::new(0);
+    settings.encoder.capture = CaptureSource::TestPattern {
+        width: 640,
+        height: 360,
+    };
+    let hosting = std::thread::spawn(move || host::host(settings, sender, receiver));
+    std::thread::sleep(Duration::from_secs(1));
+    stop_sender.send(host::UIToHostingMessage::Stop).unwrap();
+    hosting.join().unwrap();
+}
+
+fn test_frame(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn reassemble_shuffled_fragments() {
+    let frame = test_frame(10_000);
+    let mut fragments = split_into_fragments(&frame, FragmentChannel::Frame, 1, 1000).unwrap();
+    assert_eq!(fragments.len(), 10);
+    fragments.shuffle(&mut rand::rng());
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    let (last, rest) = fragments.split_last().unwrap();
+    for fragment in rest {
+        assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+    }
+    assert_eq!(reassembler.push(last, now).unwrap(), Some(frame));
+}
+
+#[test]
+fn fragments_fit_in_default_payload_size() {
+    let frame = test_frame(100_000);
+    let fragments =
+        split_into_fragments(&frame, FragmentChannel::Frame, 1, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+    assert!(
+        fragments
+            .iter()
+            .all(|fragment| fragment.len() <= DEFAULT_MAX_PAYLOAD_SIZE + FRAGMENT_HEADER_SIZE)
+    );
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    let completed = fragments
+        .iter()
+        .filter_map(|fragment| reassembler.push(fragment, now).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(completed, vec![frame]);
+}
+
+#[test]
+fn reassemble_duplicate_fragments() {
+    let frame = test_frame(3000);
+    let fragments = split_into_fragments(&frame, FragmentChannel::Frame, 1, 1000).unwrap();
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+    assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+    assert_eq!(reassembler.push(&fragments[1], now).unwrap(), None);
+    assert_eq!(reassembler.push(&fragments[2], now).unwrap(), Some(frame));
+    // A late duplicate must not resurrect the frame
+    assert_eq!(reassembler.push(&fragments[2], now).unwrap(), None);
+}
+
+#[test]
+fn dropped_fragment_does_not_corrupt_later_frames() {
+    let first_frame = test_frame(5000);
+    let second_frame = test_frame(4000);
+    let mut first_fragments =
+        split_into_fragments(&first_frame, FragmentChannel::Frame, 1, 1000).unwrap();
+    let second_fragments =
+        split_into_fragments(&second_frame, FragmentChannel::Frame, 2, 1000).unwrap();
+    first_fragments.remove(2);
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    for fragment in &first_fragments {
+        assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+    }
+    let mut completed = None;
+    for fragment in &second_fragments {
+        completed = reassembler.push(fragment, now).unwrap();
+    }
+    assert_eq!(completed, Some(second_frame));
+}
+
+#[test]
+fn incomplete_frame_times_out() {
+    let frame = test_frame(2000);
+    let fragments = split_into_fragments(&frame, FragmentChannel::Frame, 1, 1000).unwrap();
+    let timeout = Duration::from_millis(100);
+
+    let mut reassembler = Reassembler::new(timeout);
+    let now = Instant::now();
+    assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+    let later = now + timeout + Duration::from_millis(1);
+    assert_eq!(reassembler.push(&fragments[1], later).unwrap(), None);
+}
+
+#[test]
+fn older_frames_are_dropped_after_newer_frame_completes() {
+    let first_frame = test_frame(2000);
+    let second_frame = test_frame(1000);
+    let first_fragments =
+        split_into_fragments(&first_frame, FragmentChannel::Frame, 1, 1000).unwrap();
+    let second_fragments =
+        split_into_fragments(&second_frame, FragmentChannel::Frame, 2, 1000).unwrap();
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    assert_eq!(reassembler.push(&first_fragments[0], now).unwrap(), None);
+    assert_eq!(
+        reassembler.push(&second_fragments[0], now).unwrap(),
+        Some(second_frame)
+    );
+    assert_eq!(reassembler.push(&first_fragments[1], now).unwrap(), None);
+}
+
+#[test]
+fn channels_are_reassembled_independently() {
+    let frame = test_frame(2000);
+    let cursor = test_frame(1500);
+    let frame_fragments = split_into_fragments(&frame, FragmentChannel::Frame, 1, 1000).unwrap();
+    let cursor_fragments = split_into_fragments(&cursor, FragmentChannel::Cursor, 7, 1000).unwrap();
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    assert_eq!(reassembler.push(&frame_fragments[0], now).unwrap(), None);
+    assert_eq!(reassembler.push(&cursor_fragments[0], now).unwrap(), None);
+    assert_eq!(
+        reassembler.push(&cursor_fragments[1], now).unwrap(),
+        Some(cursor)
+    );
+    // A completed cursor with a higher sequence leaves the frame in progress alone
+    assert_eq!(
+        reassembler.push(&frame_fragments[1], now).unwrap(),
+        Some(frame)
+    );
+}
+
+#[test]
+fn malformed_fragment_is_rejected() {
+    let fragments =
+        split_into_fragments(&test_frame(100), FragmentChannel::Frame, 1, 1000).unwrap();
+    let truncated = &fragments[0][..fragments[0].len() - 1];
+
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.push(truncated, Instant::now()).is_err());
+}
+
+#[test]
+fn too_many_fragments_are_refused() {
+    let frame = test_frame(u16::MAX as usize);
+    assert_eq!(
+        split_into_fragments(&frame, FragmentChannel::Frame, 1, 1)
+            .unwrap()
+            .len(),
+        u16::MAX as usize
+    );
+
+    let frame = test_frame(u16::MAX as usize + 1);
+    assert!(matches!(
+        split_into_fragments(&frame, FragmentChannel::Frame, 1, 1),
+        Err(NetworkConversionError::TooManyFragments(65536))
+    ));
+}
+
+#[test]
+fn rtp_packets_are_told_apart_from_messages() {
+    let frame: Vec<u8> = HostToClientNetworkMessage::Frame(NetworkFrame {
+        feed: FeedID(0),
+        data: test_frame(100),
+        pts: None,
+    })
+    .into();
+    assert!(!rtp::is_rtp_packet(&frame));
+    for fragment in split_into_fragments(&frame, FragmentChannel::Frame, 1, 1000).unwrap() {
+        assert!(!rtp::is_rtp_packet(&fragment));
+    }
+    assert!(rtp::is_rtp_packet(&[
+        0x80, 96, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0
+    ]));
+}
+
+#[test]
+fn join_request_response_carries_stream_parameters() {
+    for codec in Codec::ALL {
+        for transport in [Transport::Quickscreen, Transport::Rtp] {
+            let parameters = StreamParameters {
+                codec,
+                transport,
+                width: 2560,
+                height: 1440,
+                framerate: 30,
+            };
+            let feeds = vec![
+                parameters,
+                StreamParameters {
+                    width: 1280,
+                    height: 1024,
+                    ..parameters
+                },
+            ];
+            let buffer: Vec<u8> =
+                HostToClientNetworkMessage::JoinRequestResponse(Ok(feeds.clone())).into();
+            let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+            assert!(matches!(
+                message,
+                HostToClientNetworkMessage::JoinRequestResponse(Ok(received)) if received == feeds
+            ));
+        }
+    }
+}
+
+#[test]
+fn frames_keep_their_timestamp() {
+    for pts in [None, Some(gstreamer::ClockTime::from_mseconds(1234))] {
+        let buffer: Vec<u8> = HostToClientNetworkMessage::Frame(NetworkFrame {
+            feed: FeedID(1),
+            data: test_frame(100),
+            pts,
+        })
+        .into();
+        let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+        assert!(matches!(
+            message,
+            HostToClientNetworkMessage::Frame(frame)
+                if frame.feed == FeedID(1) && frame.pts == pts && frame.data == test_frame(100)
+        ));
+    }
+}
+
+#[test]
+fn audio_keeps_its_timestamp() {
+    for pts in [None, Some(gstreamer::ClockTime::from_mseconds(20))] {
+        let buffer: Vec<u8> = HostToClientNetworkMessage::Audio(AudioPacket {
+            data: test_frame(120),
+            pts,
+        })
+        .into();
+        let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+        assert!(matches!(
+            message,
+            HostToClientNetworkMessage::Audio(packet)
+                if packet.pts == pts && packet.data == test_frame(120)
+        ));
+    }
+}
+
+#[test]
+fn stream_parameter_changes_round_trip() {
+    let parameters = StreamParameters {
+        codec: Codec::H264,
+        transport: Transport::Quickscreen,
+        width: 1080,
+        height: 1920,
+        framerate: 60,
+    };
+    let buffer: Vec<u8> =
+        HostToClientNetworkMessage::StreamParameters(FeedID(2), parameters).into();
+    let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+    assert!(matches!(
+        message,
+        HostToClientNetworkMessage::StreamParameters(FeedID(2), received) if received == parameters
+    ));
+
+    assert!(matches!(
+        HostToClientNetworkMessage::try_from(&buffer[..buffer.len() - 1]),
+        Err(NetworkConversionError::MalformedMessage)
+    ));
+}
+
+#[test]
+fn cursor_updates_round_trip() {
+    let bitmap = CursorBitmap {
+        width: 2,
+        height: 3,
+        hotspot: (1, 2),
+        data: (0..24).collect(),
+    };
+    for update in [
+        CursorUpdate {
+            position: Some((FeedID(0), -4, 300)),
+            bitmap: None,
+        },
+        CursorUpdate {
+            position: Some((FeedID(1), 10, 20)),
+            bitmap: Some(bitmap.clone()),
+        },
+        CursorUpdate {
+            position: None,
+            bitmap: None,
+        },
+    ] {
+        let buffer: Vec<u8> = HostToClientNetworkMessage::Cursor(update.clone()).into();
+        let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+        assert!(matches!(
+            message,
+            HostToClientNetworkMessage::Cursor(received) if received == update
+        ));
+    }
+
+    // The bitmap has to be as large as its size says
+    let buffer: Vec<u8> = HostToClientNetworkMessage::Cursor(CursorUpdate {
+        position: Some((FeedID(0), 0, 0)),
+        bitmap: Some(bitmap),
+    })
+    .into();
+    assert!(matches!(
+        HostToClientNetworkMessage::try_from(&buffer[..buffer.len() - 1]),
+        Err(NetworkConversionError::MalformedMessage)
+    ));
+}
+
+#[test]
+fn join_request_response_carries_refusal_reason() {
+    for reason in [
+        RefusalReason::RefusedByHost,
+        RefusalReason::UnsupportedCodec(Codec::Av1),
+        RefusalReason::ResolutionTooLarge(3840, 2160),
+        RefusalReason::IncompatibleVersion(7),
+    ] {
+        let buffer: Vec<u8> = HostToClientNetworkMessage::JoinRequestResponse(Err(reason)).into();
+        let message = HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap();
+        assert!(matches!(
+            message,
+            HostToClientNetworkMessage::JoinRequestResponse(Err(received)) if received == reason
+        ));
+    }
+}
+
+#[test]
+fn join_request_carries_capabilities() {
+    let capabilities = ClientCapabilities {
+        decoders: Codec::ALL.to_vec(),
+        max_resolution: (3840, 2160),
+    };
+    let buffer: Vec<u8> =
+        ClientToHostNetworkMessage::JoinRequest(ClientID(0xbeef), capabilities.clone()).into();
+    assert!(buffer.len() <= CLIENT_TO_HOST_MESSAGE_SIZE);
+
+    let message = ClientToHostNetworkMessage::try_from(buffer.as_slice()).unwrap();
+    assert!(matches!(
+        message,
+        ClientToHostNetworkMessage::JoinRequest(ClientID(0xbeef), received) if received == capabilities
+    ));
+}
+
+#[test]
+fn codecs_are_negotiated() {
+    let encoders = [Codec::H265, Codec::H264, Codec::Vp9];
+    assert_eq!(
+        Codec::negotiate(Codec::H265, &encoders, &Codec::ALL),
+        Some(Codec::H265)
+    );
+    assert_eq!(
+        Codec::negotiate(Codec::H265, &encoders, &[Codec::Vp9, Codec::H264]),
+        Some(Codec::H264)
+    );
+    assert_eq!(
+        Codec::negotiate(Codec::H265, &encoders, &[Codec::Av1, Codec::Vp8]),
+        None
+    );
+}
+
+#[test]
+fn heartbeats_round_trip() {
+    let buffer: Vec<u8> = ClientToHostNetworkMessage::Heartbeat(ClientID(0xbeef)).into();
+    assert!(matches!(
+        ClientToHostNetworkMessage::try_from(buffer.as_slice()).unwrap(),
+        ClientToHostNetworkMessage::Heartbeat(ClientID(0xbeef))
+    ));
+
+    let mut buffer: Vec<u8> = HostToClientNetworkMessage::Heartbeat.into();
+    assert!(matches!(
+        HostToClientNetworkMessage::try_from(buffer.as_slice()).unwrap(),
+        HostToClientNetworkMessage::Heartbeat
+    ));
+    buffer.push(0);
+    assert!(matches!(
+        HostToClientNetworkMessage::try_from(buffer.as_slice()),
+        Err(NetworkConversionError::MalformedMessage)
+    ));
+}
+
+#[test]
+fn messages_from_other_versions_are_rejected() {
+    let mut buffer: Vec<u8> = ClientToHostNetworkMessage::Left(ClientID(1)).into();
+    buffer[2] = PROTOCOL_VERSION + 1;
+    assert!(matches!(
+        ClientToHostNetworkMessage::try_from(buffer.as_slice()),
+        Err(NetworkConversionError::IncompatibleVersion(version)) if version == PROTOCOL_VERSION + 1
+    ));
+
+    let mut fragments =
+        split_into_fragments(&test_frame(10), FragmentChannel::Frame, 1, 1000).unwrap();
+    fragments[0][2] = PROTOCOL_VERSION + 1;
+    assert!(matches!(
+        Reassembler::default().push(&fragments[0], Instant::now()),
+        Err(NetworkConversionError::IncompatibleVersion(_))
+    ));
+}
+
+#[test]
+fn stray_packets_are_rejected() {
+    for buffer in [&[1, 0x34, 0x12][..], &[2, 1][..], &[][..]] {
+        assert!(ClientToHostNetworkMessage::try_from(buffer).is_err());
+        assert!(HostToClientNetworkMessage::try_from(buffer).is_err());
+    }
+
+    let mut buffer: Vec<u8> = ClientToHostNetworkMessage::Left(ClientID(1)).into();
+    buffer.push(0);
+    assert!(matches!(
+        ClientToHostNetworkMessage::try_from(buffer.as_slice()),
+        Err(NetworkConversionError::MalformedMessage)
+    ));
+}
+
+#[test]
+fn pacer_limits_framerate() {
+    let mut pacer = FramePacer::new(30);
+    let start = Instant::now();
+    // A compositor delivering a changing picture at 60 fps for one second
+    let sent = (0..60u64)
+        .filter(|index| {
+            let now = start + Duration::from_secs(1) * *index as u32 / 60;
+            pacer.should_send(now, None, || *index)
+        })
+        .count();
+    assert_eq!(sent, 30);
+}
+
+#[test]
+fn pacer_skips_unchanged_frames() {
+    let mut pacer = FramePacer::new(30);
+    let start = Instant::now();
+    assert!(pacer.should_send(start, None, || 1));
+    assert!(!pacer.should_send(start + Duration::from_millis(100), None, || 1));
+    assert!(pacer.should_send(start + Duration::from_millis(200), None, || 2));
+    // Static content is still refreshed now and then
+    assert!(pacer.should_send(
+        start + Duration::from_millis(200) + REFRESH_INTERVAL,
+        None,
+        || 2
+    ));
+}
+
+#[test]
+fn pacer_follows_damage() {
+    let mut pacer = FramePacer::new(10);
+    let start = Instant::now();
+    let region = DamageRegion {
+        x: 10,
+        y: 20,
+        width: 30,
+        height: 40,
+    };
+    let unreachable_hash = || panic!("frames with damage metadata are not hashed");
+    assert!(pacer.should_send(start, Some(&[]), unreachable_hash));
+
+    // Damage of a frame dropped for arriving too early is kept for the next one
+    let early = start + Duration::from_millis(50);
+    assert!(!pacer.should_send(early, Some(&[region]), unreachable_hash));
+    let on_time = start + Duration::from_millis(100);
+    assert!(pacer.should_send(on_time, Some(&[]), unreachable_hash));
+    assert_eq!(pacer.damage(), &[region]);
+
+    let undamaged = start + Duration::from_millis(200);
+    assert!(!pacer.should_send(undamaged, Some(&[]), unreachable_hash));
+}
+
+#[test]
+fn pacer_merges_excess_damage() {
+    let mut pacer = FramePacer::new(10);
+    let regions: Vec<DamageRegion> = (0..=MAX_DAMAGE_REGIONS as i32)
+        .map(|index| DamageRegion {
+            x: index * 10,
+            y: 5,
+            width: 10,
+            height: 10,
+        })
+        .collect();
+    assert!(pacer.should_send(Instant::now(), Some(&regions), || 0));
+    assert_eq!(
+        pacer.damage(),
+        &[DamageRegion {
+            x: 0,
+            y: 5,
+            width: (MAX_DAMAGE_REGIONS as u32 + 1) * 10,
+            height: 10,
+        }]
+    );
+}
+
+#[test]
+fn sampled_hash_notices_changes() {
+    let frame = test_frame(3840 * 2160 * 4);
+    let hash = sampled_hash([frame.as_slice()]);
+    assert_eq!(sampled_hash([frame.clone().as_slice()]), hash);
+
+    let mut changed = frame.clone();
+    changed[0] ^= 1;
+    assert_ne!(sampled_hash([changed.as_slice()]), hash);
+    // A whole row changing is always noticed
+    let mut changed = frame.clone();
+    changed[1000 * 3840 * 4..1001 * 3840 * 4].fill(0);
+    assert_ne!(sampled_hash([changed.as_slice()]), hash);
+
+    // Small planes are hashed whole
+    let small = test_frame(100);
+    let mut changed = small.clone();
+    changed[99] ^= 1;
+    assert_ne!(
+        sampled_hash([changed.as_slice()]),
+        sampled_hash([small.as_slice()])
+    );
+}
+
+#[test]
+fn damage_is_clipped_to_the_frame() {
+    let region = |x, y, width, height| DamageRegion {
+        x,
+        y,
+        width,
+        height,
+    };
+    assert_eq!(
+        region(10, 20, 30, 40).clip(100, 100),
+        Some(region(10, 20, 30, 40))
+    );
+    // Sticking out to the top left shrinks the region instead of moving it
+    assert_eq!(
+        region(-10, -5, 30, 40).clip(100, 100),
+        Some(region(0, 0, 20, 35))
+    );
+    assert_eq!(
+        region(90, 80, 30, 40).clip(100, 100),
+        Some(region(90, 80, 10, 20))
+    );
+    assert_eq!(region(-50, 0, 30, 40).clip(100, 100), None);
+    assert_eq!(region(100, 0, 30, 40).clip(100, 100), None);
+}
+
+#[test]
+fn uncropped_sources_are_sent_whole() {
+    let layout = Layout::new((1920, 1080), None);
+    assert_eq!(layout.region.width, 1920);
+    assert_eq!(layout.region.height, 1080);
+    assert_eq!(layout.margins(), (0, 0, 0, 0));
+    assert_eq!(layout.translate((10, 20)), Some((10, 20)));
+    assert_eq!(layout.translate((1920, 20)), None);
+}
+
+#[test]
+fn crop_region_stays_inside_the_sources() {
+    let crop = CropRegion {
+        x: 1800,
+        y: 100,
+        width: 301,
+        height: 2000,
+    };
+    let layout = Layout::new((1920, 1080), Some(crop));
+    // Clamped to the picture and rounded down to even sizes
+    assert_eq!(
+        layout.region,
+        CropRegion {
+            x: 1800,
+            y: 100,
+            width: 120,
+            height: 980,
+        }
+    );
+    assert_eq!(layout.margins(), (1800, 100, 0, 0));
+    assert_eq!(layout.translate((1810, 110)), Some((10, 10)));
+    assert_eq!(layout.translate((10, 110)), None);
+}
+
+fn arguments(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn host_arguments_are_parsed() {
+    let parsed = cli::host::parse_arguments(&arguments(&[
+        "--port",
+        "4321",
+        "--auto-accept",
+        "--codec",
+        "h.265",
+        "--cursor",
+        "metadata",
+        "--sources",
+        "window,virtual",
+        "--crop",
+        "10,20,640,480",
+        "--desktop-audio",
+        "--test-pattern",
+        "800x600",
+        "--impairment",
+        "mobile",
+    ]))
+    .unwrap();
+    assert_eq!(parsed.settings.port, 4321);
+    assert!(parsed.auto_accept);
+    assert_eq!(parsed.settings.encoder.codec, Codec::H265);
+    assert_eq!(parsed.settings.encoder.cursor_mode, CursorMode::Metadata);
+    assert_eq!(
+        parsed.settings.encoder.source_kinds,
+        vec![SourceKind::Window, SourceKind::Virtual]
+    );
+    assert_eq!(
+        parsed.settings.encoder.crop,
+        Some(CropRegion {
+            x: 10,
+            y: 20,
+            width: 640,
+            height: 480,
+        })
+    );
+    assert!(parsed.settings.encoder.desktop_audio);
+    assert!(!parsed.settings.encoder.microphone);
+    assert_eq!(parsed.settings.impairment, ImpairmentProfile::PRESETS[2].1);
+    assert_eq!(
+        parsed.settings.encoder.capture,
+        CaptureSource::TestPattern {
+            width: 800,
+            height: 600
+        }
+    );
+}
+
+#[test]
+fn rtp_destinations_imply_rtp() {
+    let parsed = cli::host::parse_arguments(&arguments(&[
+        "--rtp-destination",
+        "127.0.0.1:5004",
+        "--rtp-destination",
+        "[::1]:5006",
+    ]))
+    .unwrap();
+    assert_eq!(parsed.settings.encoder.transport, Transport::Rtp);
+    assert_eq!(
+        parsed.settings.rtp_destinations,
+        vec![
+            "127.0.0.1:5004".parse::<SocketAddr>().unwrap(),
+            "[::1]:5006".parse().unwrap()
+        ]
+    );
+}
+
+#[test]
+fn invalid_host_arguments_are_refused() {
+    for args in [
+        &["--prot", "4321"][..],
+        &["--port"],
+        &["--port", "port"],
+        &["--codec", "mpeg2"],
+        &["--crop", "10,20,640"],
+        &["--test-pattern", "800"],
+        &["--rtp-destination", "127.0.0.1"],
+        &["--impairment", "loss=2"],
+    ] {
+        assert!(cli::host::parse_arguments(&arguments(args)).is_err());
+    }
+}
+
+#[test]
+fn join_arguments_are_parsed() {
+    let parsed = cli::join::parse_arguments(&arguments(&[
+        "192.168.1.20",
+        "4321",
+        "--record",
+        "talk.mp4",
+        "--feed",
+        "1",
+        "--duration",
+        "2.5",
+        "--impairment",
+        "loss=0.1",
+    ]))
+    .unwrap();
+    assert_eq!(
+        parsed.address,
+        "192.168.1.20".parse::<std::net::IpAddr>().unwrap()
+    );
+    assert_eq!(parsed.port, 4321);
+    assert_eq!(parsed.target, Some(RecordTarget::File("talk.mp4".into())));
+    assert_eq!(parsed.feed, FeedID(1));
+    assert_eq!(parsed.duration, Some(Duration::from_millis(2500)));
+    assert_eq!(
+        parsed.impairment,
+        ImpairmentProfile {
+            loss: 0.1,
+            ..ImpairmentProfile::NONE
+        }
+    );
+    // Recording is enough to do, stats are only printed when asked for
+    assert!(!parsed.stats);
+
+    let parsed = cli::join::parse_arguments(&arguments(&["::1", "4321"])).unwrap();
+    assert_eq!(parsed.target, None);
+    assert!(parsed.stats);
+
+    assert!(cli::join::parse_arguments(&arguments(&["::1"])).is_err());
+    assert!(cli::join::parse_arguments(&arguments(&["host", "4321"])).is_err());
+    for duration in ["-1", "inf", "NaN"] {
+        assert!(
+            cli::join::parse_arguments(&arguments(&["::1", "4321", "--duration", duration]))
+                .is_err()
+        );
+    }
+}
+
+#[test]
+fn recordings_are_muxed_by_extension() {
+    assert_eq!(
+        RecordTarget::File("talk.MP4".into()).muxer(),
+        Some("mp4mux")
+    );
+    assert_eq!(
+        RecordTarget::File("talk.mkv".into()).muxer(),
+        Some("matroskamux")
+    );
+    assert_eq!(
+        RecordTarget::File("talk".into()).muxer(),
+        Some("matroskamux")
+    );
+    assert_eq!(
+        RecordTarget::VideoDevice("/dev/video9".into()).muxer(),
+        None
+    );
+}
+
+#[test]
+fn impairment_profiles_are_parsed() {
+    assert_eq!("none".parse(), Ok(ImpairmentProfile::NONE));
+    assert_eq!(
+        "mobile".parse::<ImpairmentProfile>().unwrap().bandwidth,
+        Some(4000)
+    );
+    assert_eq!(
+        "loss=0.1,delay=40,jitter=2.5,bandwidth=800".parse(),
+        Ok(ImpairmentProfile {
+            loss: 0.1,
+            delay: Duration::from_millis(40),
+            jitter: Duration::from_micros(2500),
+            bandwidth: Some(800),
+            ..ImpairmentProfile::NONE
+        })
+    );
+    for invalid in [
+        "lossy",
+        "loss=2",
+        "delay=-5",
+        "delay=inf",
+        "jitter=1e300",
+        "bandwidth=inf",
+        "speed=10",
+        "loss=",
+    ] {
+        assert!(invalid.parse::<ImpairmentProfile>().is_err(), "{}", invalid);
+    }
+}
+
+/// Sends `count` numbered datagrams through `profile`, returning the numbers that arrived in
+/// arrival order
+fn send_impaired(profile: ImpairmentProfile, count: u8, wait: Duration) -> Vec<u8> {
+    let receiver = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    receiver.set_nonblocking(true).unwrap();
+    let sender = ImpairedSocket::with_seed(
+        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap(),
+        profile,
+        7,
+    );
+    let address = receiver.local_addr().unwrap();
+    for number in 0..count {
+        sender.send_to(&[number; 100], address).unwrap();
+    }
+    std::thread::sleep(wait);
+    sender.flush();
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut buffer = [0; 200];
+    std::iter::from_fn(|| receiver.recv(&mut buffer).ok().map(|_| buffer[0])).collect()
+}
+
+#[test]
+fn impaired_socket_reorders_and_duplicates() {
+    let unimpaired = send_impaired(ImpairmentProfile::NONE, 4, Duration::ZERO);
+    assert_eq!(unimpaired, [0, 1, 2, 3]);
+
+    let reordering = ImpairmentProfile {
+        reordering: 1.,
+        ..ImpairmentProfile::NONE
+    };
+    assert_eq!(send_impaired(reordering, 4, Duration::ZERO), [1, 0, 3, 2]);
+    // With nothing following it, the last one still goes out eventually
+    assert_eq!(
+        send_impaired(reordering, 3, Duration::from_millis(100)),
+        [1, 0, 2]
+    );
+
+    let duplication = ImpairmentProfile {
+        duplication: 1.,
+        ..ImpairmentProfile::NONE
+    };
+    assert_eq!(
+        send_impaired(duplication, 3, Duration::ZERO),
+        [0, 0, 1, 1, 2, 2]
+    );
+}
+
+#[test]
+fn impaired_socket_loses_delays_and_caps() {
+    let loss = ImpairmentProfile {
+        loss: 0.5,
+        ..ImpairmentProfile::NONE
+    };
+    let arrived = send_impaired(loss, 100, Duration::ZERO).len();
+    assert!((25..75).contains(&arrived), "{} of 100 arrived", arrived);
+
+    let delay = ImpairmentProfile {
+        delay: Duration::from_secs(10),
+        ..ImpairmentProfile::NONE
+    };
+    assert!(send_impaired(delay, 3, Duration::ZERO).is_empty());
+    let delay = ImpairmentProfile {
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(20),
+        ..ImpairmentProfile::NONE
+    };
+    assert_eq!(send_impaired(delay, 3, Duration::from_millis(100)).len(), 3);
+
+    // 100 bytes take 100ms at 8 kbit/s, the queue holds 200ms worth of them
+    let bandwidth = ImpairmentProfile {
+        bandwidth: Some(8),
+        ..ImpairmentProfile::NONE
+    };
+    assert_eq!(
+        send_impaired(bandwidth, 10, Duration::from_millis(400)),
+        [0, 1, 2]
+    );
+}
+
+/// What the loopback proxy does with a datagram from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fate {
+    Deliver,
+    Drop,
+    /// Delivered right after the next delivered datagram, swapping their order
+    Hold,
+}
+
+/// Picks the [`Fate`] of every datagram the host sends, given how many came before it
+type PacketHook = Box<dyn FnMut(usize, &[u8]) -> Fate + Send>;
+
+/// How long the loopback harness waits for anything before failing the test
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A host sharing a test pattern and a client joining it through a proxy, all on localhost
+struct Loopback {
+    host_sender: mpsc::Sender<host::UIToHostingMessage>,
+    host_receiver: mpsc::Receiver<host::HostingToUIMessage>,
+    join_sender: mpsc::Sender<UIToJoinedMessage>,
+    join_receiver: mpsc::Receiver<JoinedToUIMessage>,
+    /// Datagrams the host sent to the client so far
+    host_datagrams: Arc<AtomicUsize>,
+    /// Drops everything the client sends while set, as if it crashed
+    client_muted: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Loopback {
+    /// Every socket gets whatever port the system picks, so tests can run side by side
+    fn start(mut hook: PacketHook) -> Self {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let mut settings = host::HostSettings::new(0);
+        settings.encoder.capture = CaptureSource::TestPattern {
+            width: 640,
+            height: 360,
+        };
+        let (host_ui_sender, host_receiver) = mpsc::channel();
+        let (host_sender, host_ui_receiver) = mpsc::channel();
+        let mut threads = vec![std::thread::spawn(move || {
+            host::host(settings, host_ui_sender, host_ui_receiver)
+        })];
+        let host_address = SocketAddr::new(localhost, wait_for_host(&host_receiver));
+
+        // Faces the client, which only listens to the address it joined
+        let downstream = UdpSocket::bind(SocketAddr::new(localhost, 0)).unwrap();
+        let proxy_port = downstream.local_addr().unwrap().port();
+        // Learned from the first datagram the client sends, its join request
+        let client_address = Arc::new(OnceLock::new());
+        // Faces the host, to which it is the client
+        let upstream = UdpSocket::bind(SocketAddr::new(localhost, 0)).unwrap();
+        for socket in [&downstream, &upstream] {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let host_datagrams = Arc::new(AtomicUsize::new(0));
+        let client_muted = Arc::new(AtomicBool::new(false));
+
+        let (from_client, to_host) = (
+            downstream.try_clone().unwrap(),
+            upstream.try_clone().unwrap(),
+        );
+        let (is_running, is_muted, client) = (
+            running.clone(),
+            client_muted.clone(),
+            client_address.clone(),
+        );
+        threads.push(std::thread::spawn(move || {
+            let buffer = &mut [0; CLIENT_TO_HOST_MESSAGE_SIZE];
+            while is_running.load(Ordering::Relaxed) {
+                if let Ok((length, origin)) = from_client.recv_from(buffer)
+                    && !is_muted.load(Ordering::Relaxed)
+                {
+                    client.get_or_init(|| origin);
+                    let _ = to_host.send_to(&buffer[..length], host_address);
+                }
+            }
+        }));
+        let (is_running, counter) = (running.clone(), host_datagrams.clone());
+        threads.push(std::thread::spawn(move || {
+            let buffer = &mut [0; HOST_TO_CLIENT_MESSAGE_SIZE];
+            let mut held: Vec<Vec<u8>> = Vec::new();
+            while is_running.load(Ordering::Relaxed) {
+                let Ok(length) = upstream.recv(buffer) else {
+                    continue;
+                };
+                let datagram = &buffer[..length];
+                // The host only ever answers the client
+                let client_address = *client_address.get().unwrap();
+                match hook(counter.fetch_add(1, Ordering::Relaxed), datagram) {
+                    Fate::Deliver => {
+                        let _ = downstream.send_to(datagram, client_address);
+                        for datagram in held.drain(..) {
+                            let _ = downstream.send_to(&datagram, client_address);
+                        }
+                    }
+                    Fate::Drop => {}
+                    Fate::Hold => held.push(datagram.to_vec()),
+                }
+            }
+        }));
+
+        let (join_ui_sender, join_receiver) = mpsc::channel();
+        let (join_sender, join_ui_receiver) = mpsc::channel();
+        threads.push(std::thread::spawn(move || {
+            join::join(
+                localhost,
+                proxy_port,
+                ImpairmentProfile::from_env(),
+                join_ui_sender,
+                join_ui_receiver,
+            )
+        }));
+
+        Self {
+            host_sender,
+            host_receiver,
+            join_sender,
+            join_receiver,
+            host_datagrams,
+            client_muted,
+            running,
+            threads,
+        }
+    }
+
+    fn answer_join_request(&self, accepted: bool) -> ClientID {
+        let client_id = match self.host_receiver.recv_timeout(LOOPBACK_TIMEOUT).unwrap() {
+            host::HostingToUIMessage::JoinRequest(client_id) => client_id,
+            _ => panic!("Expected a join request"),
+        };
+        self.host_sender
+            .send(host::UIToHostingMessage::JoinRequestResponse(
+                client_id, accepted,
+            ))
+            .unwrap();
+        client_id
+    }
+
+    /// The next message of the client that `wanted` picks, skipping the others
+    fn wait_for_client(&self, wanted: impl Fn(&JoinedToUIMessage) -> bool) -> JoinedToUIMessage {
+        let deadline = Instant::now() + LOOPBACK_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = self.join_receiver.recv_timeout(remaining).unwrap();
+            if wanted(&message) {
+                return message;
+            }
+        }
+    }
+
+    fn wait_for_frames(&self, amount: usize) {
+        for _ in 0..amount {
+            self.wait_for_client(|message| matches!(message, JoinedToUIMessage::Frame(..)));
+        }
+    }
+
+    fn stop(self) {
+        let _ = self.join_sender.send(UIToJoinedMessage::Leave);
+        let _ = self.host_sender.send(host::UIToHostingMessage::Stop);
+        self.running.store(false, Ordering::Relaxed);
+        for thread in self.threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Waits until the host listens, returning the port it got
+fn wait_for_host(host_receiver: &mpsc::Receiver<host::HostingToUIMessage>) -> u16 {
+    match host_receiver.recv_timeout(LOOPBACK_TIMEOUT).unwrap() {
+        host::HostingToUIMessage::Listening(port) => port,
+        _ => panic!("The host did not start listening"),
+    }
+}
+
+#[test]
+fn loopback_client_receives_frames_until_it_leaves() {
+    let loopback = Loopback::start(Box::new(|_, _| Fate::Deliver));
+    let client_id = loopback.answer_join_request(true);
+    let response = loopback
+        .wait_for_client(|message| matches!(message, JoinedToUIMessage::JoinRequestResponse(_)));
+    assert!(matches!(
+        response,
+        JoinedToUIMessage::JoinRequestResponse(Ok(feeds))
+            if feeds.len() == 1 && feeds[0].width == 640 && feeds[0].height == 360
+    ));
+    loopback.wait_for_frames(5);
+
+    loopback.join_sender.send(UIToJoinedMessage::Leave).unwrap();
+    assert!(matches!(
+        loopback.host_receiver.recv_timeout(LOOPBACK_TIMEOUT).unwrap(),
+        host::HostingToUIMessage::ClientLeft(id, host::LeaveReason::Left) if id == client_id
+    ));
+    // Frames already on their way still arrive, nothing is sent after them
+    std::thread::sleep(Duration::from_millis(200));
+    let sent = loopback.host_datagrams.load(Ordering::Relaxed);
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(loopback.host_datagrams.load(Ordering::Relaxed), sent);
+    loopback.stop();
+}
+
+#[test]
+fn loopback_refused_client_is_told() {
+    let loopback = Loopback::start(Box::new(|_, _| Fate::Deliver));
+    loopback.answer_join_request(false);
+    let response = loopback
+        .wait_for_client(|message| matches!(message, JoinedToUIMessage::JoinRequestResponse(_)));
+    assert!(matches!(
+        response,
+        JoinedToUIMessage::JoinRequestResponse(Err(RefusalReason::RefusedByHost))
+    ));
+    assert_eq!(loopback.host_datagrams.load(Ordering::Relaxed), 1);
+    loopback.stop();
+}
+
+#[test]
+fn loopback_lost_acceptance_is_sent_again() {
+    let loopback = Loopback::start(Box::new(|index, _| match index {
+        0 => Fate::Drop,
+        _ => Fate::Deliver,
+    }));
+    loopback.answer_join_request(true);
+    loopback.wait_for_client(|message| {
+        matches!(message, JoinedToUIMessage::JoinRequestResponse(Ok(_)))
+    });
+    loopback.wait_for_frames(5);
+    loopback.stop();
+}
+
+#[test]
+fn loopback_survives_loss_and_reordering() {
+    // Only frame fragments are impaired, so joining itself always works
+    let hook = Box::new(|index: usize, datagram: &[u8]| {
+        if !is_fragment(datagram) {
+            return Fate::Deliver;
+        }
+        match index % 50 {
+            10 => Fate::Drop,
+            20 | 35 => Fate::Hold,
+            _ => Fate::Deliver,
+        }
+    });
+    let loopback = Loopback::start(hook);
+    loopback.answer_join_request(true);
+    loopback.wait_for_frames(10);
+    loopback.stop();
+}
+
+#[test]
+fn loopback_vanished_client_and_host_time_out() {
+    let loopback = Loopback::start(Box::new(|_, _| Fate::Deliver));
+    let client_id = loopback.answer_join_request(true);
+    loopback.wait_for_frames(5);
+
+    // The host stops sending to the silent client, which in turn loses the host
+    loopback.client_muted.store(true, Ordering::Relaxed);
+    assert!(matches!(
+        loopback.host_receiver.recv_timeout(LOOPBACK_TIMEOUT).unwrap(),
+        host::HostingToUIMessage::ClientLeft(id, host::LeaveReason::TimedOut) if id == client_id
+    ));
+    loopback.wait_for_client(|message| matches!(message, JoinedToUIMessage::HostLost));
+    loopback.stop();
+}
+
+#[test]
+fn rtp_destinations_get_what_the_session_description_describes() {
+    let player = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    player.set_read_timeout(Some(LOOPBACK_TIMEOUT)).unwrap();
+    let destination = player.local_addr().unwrap();
+    let codec = *Codec::available_encoders()
+        .first()
+        .expect("Needs x265enc, x264enc, svtav1enc, rav1enc, vp9enc or vp8enc");
+
+    let mut settings = host::HostSettings::new(0);
+    settings.encoder.capture = CaptureSource::TestPattern {
+        width: 640,
+        height: 360,
+    };
+    settings.encoder.transport = Transport::Rtp;
+    settings.encoder.codec = codec;
+    settings.rtp_destinations = vec![destination];
+    let (sender, _receiver) = mpsc::channel();
+    let (stop_sender, receiver) = mpsc::channel();
+    let hosting = std::thread::spawn(move || host::host(settings, sender, receiver));
+
+    // No join request, the player only knows the session description
+    let description = rtp::session_description(destination, codec);
+    let media = description
+        .lines()
+        .find_map(|line| line.strip_prefix("m=video "))
+        .unwrap();
+    let [port, "RTP/AVP", payload_type] = media.split(' ').collect::<Vec<_>>()[..] else {
+        panic!("Unexpected media line {}", media);
+    };
+    assert_eq!(port.parse::<u16>().unwrap(), destination.port());
+    let payload_type: u8 = payload_type.parse().unwrap();
+    assert!(description.lines().any(|line| line
+        == format!(
+            "a=rtpmap:{} {}/{}",
+            payload_type,
+            codec.rtp_encoding_name(),
+            rtp::CLOCK_RATE
+        )));
+
+    let mut buffer = [0; HOST_TO_CLIENT_MESSAGE_SIZE];
+    for _ in 0..10 {
+        let length = player.recv(&mut buffer).unwrap();
+        let packet = &buffer[..length];
+        assert!(rtp::is_rtp_packet(packet));
+        assert_eq!(packet[1] & 0x7f, payload_type);
+        assert!(length <= rtp::MTU as usize);
+    }
+
+    stop_sender.send(host::UIToHostingMessage::Stop).unwrap();
+    hosting.join().unwrap();
 }
@@ -1,6 +1,9 @@
 use crate::{
-    encoding::network::ClientID,
-    host::{HostingToUIMessage, UIToHostingMessage},
+    encoding::{
+        self, CursorMode, EncoderSettings, SourceKind, Transport, codec::Codec, layout::CropRegion,
+        network::ClientID, pacing,
+    },
+    host::{HostSettings, HostingToUIMessage, LeaveReason, UIToHostingMessage},
 };
 use libadwaita::{
     AlertDialog,
@@ -10,8 +13,11 @@ use libadwaita::{
         object::{IsA, ObjectExt},
     },
     gtk::{
-        Align, Button, Entry, EntryBuffer, Label, Stack, Widget,
-        prelude::{BoxExt, ButtonExt, EditableExt, EditableExtManual, EntryBufferExtManual},
+        Align, Button, CheckButton, DropDown, Entry, EntryBuffer, Label, SpinButton, Stack, Widget,
+        prelude::{
+            BoxExt, ButtonExt, CheckButtonExt, EditableExt, EditableExtManual,
+            EntryBufferExtManual, WidgetExt,
+        },
     },
     prelude::{AdwDialogExt, AlertDialogExt, AlertDialogExtManual},
 };
@@ -66,6 +72,178 @@ pub fn build_page() -> impl IsA<Widget> {
             }
         });
 
+    let codec_label = Label::builder()
+        .label("Preferred codec")
+        .halign(Align::Start)
+        .build();
+    let codec_names: Vec<&str> = Codec::ALL.iter().map(Codec::name).collect();
+    let codec_dropdown = DropDown::from_strings(&codec_names);
+    let codec_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .width_request(200)
+        .build();
+    codec_box.append(&codec_label);
+    codec_box.append(&codec_dropdown);
+
+    let framerate_label = Label::builder()
+        .label("Framerate")
+        .halign(Align::Start)
+        .build();
+    let framerate_input = SpinButton::with_range(
+        pacing::MIN_FRAMERATE as f64,
+        pacing::MAX_FRAMERATE as f64,
+        5.,
+    );
+    framerate_input.set_value(EncoderSettings::default().framerate as f64);
+    let framerate_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .width_request(200)
+        .build();
+    framerate_box.append(&framerate_label);
+    framerate_box.append(&framerate_input);
+
+    let cursor_label = Label::builder()
+        .label("Cursor")
+        .halign(Align::Start)
+        .build();
+    let cursor_mode_names: Vec<&str> = CursorMode::ALL.iter().map(CursorMode::name).collect();
+    let cursor_dropdown = DropDown::from_strings(&cursor_mode_names);
+    cursor_dropdown.set_selected(
+        CursorMode::ALL
+            .iter()
+            .position(|mode| *mode == CursorMode::default())
+            .unwrap_or_default() as u32,
+    );
+    let cursor_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .width_request(200)
+        .build();
+    cursor_box.append(&cursor_label);
+    cursor_box.append(&cursor_dropdown);
+
+    let sources_label = Label::builder().label("Share").halign(Align::Start).build();
+    let source_kind_buttons: Vec<CheckButton> = SourceKind::ALL
+        .iter()
+        .map(|kind| {
+            CheckButton::builder()
+                .label(kind.name())
+                .active(EncoderSettings::default().source_kinds.contains(kind))
+                .build()
+        })
+        .collect();
+    let source_kinds_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Horizontal)
+        .spacing(4)
+        .build();
+    for button in &source_kind_buttons {
+        source_kinds_box.append(button);
+    }
+    let multiple_sources_check_button = CheckButton::builder()
+        .label("Pick several")
+        .tooltip_text("Sends every picked source as its own feed, viewers pick which to watch")
+        .build();
+    let sources_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .width_request(200)
+        .build();
+    sources_box.append(&sources_label);
+    sources_box.append(&source_kinds_box);
+    sources_box.append(&multiple_sources_check_button);
+
+    let crop_check_button = CheckButton::builder().label("Share only a region").build();
+    // x, y, width and height of the region
+    let crop_inputs: Vec<SpinButton> = ["X", "Y", "Width", "Height"]
+        .iter()
+        .map(|name| {
+            let input = SpinButton::with_range(0., 16384., 1.);
+            input.set_tooltip_text(Some(name));
+            input
+        })
+        .collect();
+    crop_inputs[2].set_value(1280.);
+    crop_inputs[3].set_value(720.);
+    let crop_inputs_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Horizontal)
+        .spacing(4)
+        .sensitive(false)
+        .build();
+    for input in &crop_inputs {
+        crop_inputs_box.append(input);
+    }
+    crop_check_button
+        .bind_property("active", &crop_inputs_box, "sensitive")
+        .build();
+    let crop_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .build();
+    crop_box.append(&crop_check_button);
+    crop_box.append(&crop_inputs_box);
+
+    let desktop_audio_check_button = CheckButton::builder()
+        .label("Share desktop audio")
+        .tooltip_text("Not sent as plain RTP")
+        .build();
+    let microphone_check_button = CheckButton::builder().label("Share microphone").build();
+    let audio_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Horizontal)
+        .spacing(4)
+        .halign(Align::Center)
+        .build();
+    audio_box.append(&desktop_audio_check_button);
+    audio_box.append(&microphone_check_button);
+
+    let rtp_check_button = CheckButton::builder()
+        .label("Send as plain RTP")
+        .tooltip_text("Lets accepted clients watch the stream in players like VLC or ffplay")
+        .halign(Align::Center)
+        .build();
+    let rtp_destinations_buffer = EntryBuffer::new(None::<String>);
+    let rtp_destinations_input = Entry::builder()
+        .placeholder_text("192.168.1.30:5004, ...")
+        .tooltip_text(
+            "Players that get the stream without joining, the session description to open is \
+             printed when hosting starts",
+        )
+        .buffer(&rtp_destinations_buffer)
+        .sensitive(false)
+        .build();
+    rtp_check_button
+        .bind_property("active", &rtp_destinations_input, "sensitive")
+        .build();
+    let rtp_box = libadwaita::gtk::Box::builder()
+        .orientation(libadwaita::gtk::Orientation::Vertical)
+        .spacing(4)
+        .halign(Align::Center)
+        .width_request(200)
+        .build();
+    rtp_box.append(&rtp_check_button);
+    rtp_box.append(&rtp_destinations_input);
+
+    let forget_source_button = Button::builder()
+        .label("Forget saved source")
+        .tooltip_text("Asks again what to share next time you host")
+        .sensitive(encoding::has_saved_source())
+        .width_request(200)
+        .halign(Align::Center)
+        .build();
+    forget_source_button.connect_clicked(|button| {
+        if let Err(error) = encoding::forget_saved_source() {
+            eprintln!("Failed to forget the saved source: {}", error);
+            return;
+        }
+        button.set_sensitive(false);
+    });
+
     let host_button = Button::builder()
         .label("Host")
         .css_classes(["suggested-action"])
@@ -80,6 +258,14 @@ pub fn build_page() -> impl IsA<Widget> {
         .build();
     host_page.append(&title);
     host_page.append(&port_box);
+    host_page.append(&codec_box);
+    host_page.append(&framerate_box);
+    host_page.append(&cursor_box);
+    host_page.append(&sources_box);
+    host_page.append(&crop_box);
+    host_page.append(&audio_box);
+    host_page.append(&rtp_box);
+    host_page.append(&forget_source_button);
     host_page.append(&host_button);
 
     let title = Label::builder()
@@ -132,7 +318,44 @@ pub fn build_page() -> impl IsA<Widget> {
         if port_buffer.text().len() < 4 {
             return;
         }
-        let (sender, receiver) = start_hosting(port_buffer.text().to_string(), &state_clone);
+        let mut settings = HostSettings::new(port_buffer.text().parse().unwrap());
+        settings.encoder.transport = if rtp_check_button.is_active() {
+            Transport::Rtp
+        } else {
+            Transport::Quickscreen
+        };
+        if rtp_check_button.is_active() {
+            let Ok(destinations) = rtp_destinations_buffer
+                .text()
+                .split(',')
+                .map(str::trim)
+                .filter(|destination| !destination.is_empty())
+                .map(str::parse)
+                .collect()
+            else {
+                return;
+            };
+            settings.rtp_destinations = destinations;
+        }
+        settings.encoder.codec = Codec::ALL[codec_dropdown.selected() as usize];
+        settings.encoder.framerate = framerate_input.value_as_int() as u32;
+        settings.encoder.cursor_mode = CursorMode::ALL[cursor_dropdown.selected() as usize];
+        settings.encoder.source_kinds = SourceKind::ALL
+            .into_iter()
+            .zip(&source_kind_buttons)
+            .filter(|(_, button)| button.is_active())
+            .map(|(kind, _)| kind)
+            .collect();
+        settings.encoder.multiple_sources = multiple_sources_check_button.is_active();
+        settings.encoder.crop = crop_check_button.is_active().then(|| CropRegion {
+            x: crop_inputs[0].value_as_int() as u32,
+            y: crop_inputs[1].value_as_int() as u32,
+            width: crop_inputs[2].value_as_int() as u32,
+            height: crop_inputs[3].value_as_int() as u32,
+        });
+        settings.encoder.desktop_audio = desktop_audio_check_button.is_active();
+        settings.encoder.microphone = microphone_check_button.is_active();
+        let (sender, receiver) = start_hosting(settings, &state_clone);
         *state_clone.message_sender.lock().unwrap() = Some(sender);
         *state_clone.message_receiver.lock().unwrap() = Some(receiver);
         stack_clone.set_visible_child(&hosting_page);
@@ -148,6 +371,8 @@ pub fn build_page() -> impl IsA<Widget> {
             .unwrap()
             .send(UIToHostingMessage::Stop)
             .unwrap();
+        // Hosting saved the picked source
+        forget_source_button.set_sensitive(encoding::has_saved_source());
         stack_clone.set_visible_child(&host_page);
     });
 
@@ -155,16 +380,15 @@ pub fn build_page() -> impl IsA<Widget> {
 }
 
 fn start_hosting(
-    port_string: String,
+    settings: HostSettings,
     state: &HostState,
 ) -> (Sender<UIToHostingMessage>, Receiver<HostingToUIMessage>) {
-    let port: u16 = port_string.parse().unwrap();
-    println!("Hosting on port {}", port_string);
+    println!("Hosting on port {}", settings.port);
 
     let (sender0, receiver0) = mpsc::channel::<HostingToUIMessage>();
     let (sender1, receiver1) = mpsc::channel::<UIToHostingMessage>();
 
-    std::thread::spawn(move || crate::host::host(port, sender0, receiver1));
+    std::thread::spawn(move || crate::host::host(settings, sender0, receiver1));
 
     let mut state_clone = state.clone();
     libadwaita::glib::timeout_add_local(Duration::from_millis(100), move || {
@@ -183,8 +407,11 @@ fn listen_for_message(state: &mut HostState) {
     let receiver = receiver.as_mut().unwrap();
     if let Ok(message) = receiver.try_recv() {
         match message {
+            HostingToUIMessage::Listening(_) => {}
             HostingToUIMessage::JoinRequest(client_id) => handle_join_request(client_id, state),
-            HostingToUIMessage::ClientLeft(client_id) => handle_client_left(client_id, state),
+            HostingToUIMessage::ClientLeft(client_id, reason) => {
+                handle_client_left(client_id, reason, state)
+            }
         }
     }
 }
@@ -220,12 +447,12 @@ fn handle_join_request_respone(
     }
 }
 
-fn handle_client_left(client_id: ClientID, state: &HostState) {
+fn handle_client_left(client_id: ClientID, reason: LeaveReason, state: &HostState) {
     state.info_dialog.set_title("Client left");
     state.info_dialog.set_heading(Some("Client left"));
     state
         .info_dialog
-        .set_body(&format!("Client {} left", client_id.0));
+        .set_body(&format!("Client {} {}", client_id.0, reason));
     state
         .info_dialog
         .clone()
@@ -1,5 +1,8 @@
 use crate::{
-    encoding::NetworkFrame,
+    encoding::{
+        CursorUpdate, DecodedFrame, FeedID, StreamParameters, impairment::ImpairmentProfile,
+        network::RefusalReason,
+    },
     join::{JoinedToUIMessage, UIToJoinedMessage},
 };
 use libadwaita::{
@@ -7,8 +10,8 @@ use libadwaita::{
     gio::Cancellable,
     glib::object::{IsA, ObjectExt},
     gtk::{
-        Align, Button, DrawingArea, Entry, EntryBuffer, Label, Stack, Widget,
-        cairo::Surface,
+        Align, Button, DrawingArea, DropDown, Entry, EntryBuffer, Label, Stack, StringList, Widget,
+        cairo::{Context, ImageSurface},
         prelude::{
             BoxExt, ButtonExt, DrawingAreaExtManual, EditableExt, EditableExtManual,
             EntryBufferExtManual, WidgetExt,
@@ -30,7 +33,22 @@ struct JoinState {
     message_receiver: Rc<RefCell<Option<Receiver<JoinedToUIMessage>>>>,
     join_request_response_dialog: AlertDialog,
     parent_widget: Stack,
-    current_frame: Rc<RefCell<Option<Surface>>>,
+    drawing_area: DrawingArea,
+    /// Picks one of the feeds, or all of them tiled
+    feed_dropdown: DropDown,
+    /// Indexed by [`FeedID`]
+    feeds: Rc<RefCell<Vec<StreamParameters>>>,
+    /// Last frame of every feed, indexed by [`FeedID`]
+    current_frames: Rc<RefCell<Vec<Option<ImageSurface>>>>,
+    cursor: Rc<RefCell<CursorState>>,
+}
+
+/// The host's cursor, when it is sent next to the frames
+#[derive(Debug, Default)]
+struct CursorState {
+    position: Option<(FeedID, i32, i32)>,
+    image: Option<ImageSurface>,
+    hotspot: (u16, u16),
 }
 
 pub fn build_page() -> impl IsA<Widget> {
@@ -133,7 +151,13 @@ pub fn build_page() -> impl IsA<Widget> {
         .content_height(720)
         .height_request(360)
         .width_request(640)
+        .build();
+
+    // Only shown when the host shares several feeds
+    let feed_dropdown = DropDown::builder()
         .visible(false)
+        .halign(Align::Center)
+        .width_request(200)
         .build();
 
     let leave_button = Button::builder()
@@ -157,6 +181,7 @@ pub fn build_page() -> impl IsA<Widget> {
         .spacing(16)
         .build();
     joined_page.append(&title);
+    joined_page.append(&feed_dropdown);
     joined_page.append(&drawing_area);
     joined_page.append(&leave_button);
 
@@ -172,6 +197,8 @@ pub fn build_page() -> impl IsA<Widget> {
     let state = JoinState {
         join_request_response_dialog,
         parent_widget: stack.clone(),
+        drawing_area: drawing_area.clone(),
+        feed_dropdown: feed_dropdown.clone(),
         ..Default::default()
     };
 
@@ -205,30 +232,117 @@ pub fn build_page() -> impl IsA<Widget> {
         stack_clone.set_visible_child_name("join-page");
     });
 
-    drawing_area.set_draw_func(move |drawing_area, cr, _, _| {
-        if let Some(texture) = &*state.current_frame.borrow() {
-            cr.set_source_surface(texture, 0., 0.).unwrap();
-            drawing_area.set_visible(true);
-        } else {
-            cr.set_source_rgb(0., 0., 0.);
+    let state_clone = state.clone();
+    feed_dropdown.connect_selected_notify(move |_| {
+        fit_shown_feed(&state_clone);
+        state_clone.drawing_area.queue_draw();
+    });
+
+    let state_clone = state.clone();
+    drawing_area.set_draw_func(move |_, cr, width, height| {
+        cr.set_source_rgb(0., 0., 0.);
+        cr.paint().unwrap();
+
+        let frames = state_clone.current_frames.borrow();
+        let shown: Vec<usize> = match shown_feed(&state_clone) {
+            Some(feed) => vec![feed.0 as usize],
+            None => (0..frames.len()).collect(),
+        };
+        // Tiles fill rows first, in a grid as close to square as possible
+        let columns = (shown.len() as f64).sqrt().ceil().max(1.) as usize;
+        let rows = shown.len().div_ceil(columns).max(1);
+        let tile_width = width as f64 / columns as f64;
+        let tile_height = height as f64 / rows as f64;
+        let cursor = state_clone.cursor.borrow();
+        for (tile, feed) in shown.into_iter().enumerate() {
+            let Some(Some(frame)) = frames.get(feed) else {
+                continue;
+            };
+            cr.save().unwrap();
+            cr.translate(
+                (tile % columns) as f64 * tile_width,
+                (tile / columns) as f64 * tile_height,
+            );
+            draw_feed(cr, frame, (tile_width, tile_height), feed, &cursor);
+            cr.restore().unwrap();
         }
     });
 
     let sender_clone = state.message_sender.clone();
     let stack_clone = stack.clone();
     leave_button.connect_clicked(move |_| {
-        sender_clone
+        // Joining already ended when the host was lost
+        let _ = sender_clone
             .borrow()
             .clone()
             .unwrap()
-            .send(UIToJoinedMessage::Leave)
-            .unwrap();
+            .send(UIToJoinedMessage::Leave);
         stack_clone.set_visible_child_name("join-page");
     });
 
     stack
 }
 
+/// Draws a frame fitted in a tile of `size` at the origin, while keeping its aspect ratio
+fn draw_feed(
+    cr: &Context,
+    frame: &ImageSurface,
+    size: (f64, f64),
+    feed: usize,
+    cursor: &CursorState,
+) {
+    let (width, height) = size;
+    let scale = (width / frame.width() as f64).min(height / frame.height() as f64);
+    cr.translate(
+        (width - frame.width() as f64 * scale) / 2.,
+        (height - frame.height() as f64 * scale) / 2.,
+    );
+    cr.scale(scale, scale);
+    cr.set_source_surface(frame, 0., 0.).unwrap();
+    cr.paint().unwrap();
+
+    // The cursor position is in frame coordinates, so it is drawn on top with the same
+    // transformation
+    if let (Some((cursor_feed, x, y)), Some(image)) = (cursor.position, &cursor.image)
+        && cursor_feed.0 as usize == feed
+    {
+        cr.set_source_surface(
+            image,
+            (x - cursor.hotspot.0 as i32) as f64,
+            (y - cursor.hotspot.1 as i32) as f64,
+        )
+        .unwrap();
+        cr.paint().unwrap();
+    }
+}
+
+/// The feed picked in the dropdown, `None` when all of them are tiled
+fn shown_feed(state: &JoinState) -> Option<FeedID> {
+    let feed_count = state.feeds.borrow().len();
+    if feed_count <= 1 {
+        return Some(FeedID(0));
+    }
+    let selected = state.feed_dropdown.selected() as usize;
+    (selected < feed_count).then_some(FeedID(selected as u8))
+}
+
+/// Gives the drawing area the aspect ratio of the shown feed
+fn fit_shown_feed(state: &JoinState) {
+    let Some(feed) = shown_feed(state) else {
+        return;
+    };
+    let Some(stream_parameters) = state.feeds.borrow().get(feed.0 as usize).copied() else {
+        return;
+    };
+    if stream_parameters.width == 0 || stream_parameters.height == 0 {
+        return;
+    }
+    let width = state.drawing_area.content_width();
+    state.drawing_area.set_content_height(
+        width * stream_parameters.height as i32 / stream_parameters.width as i32,
+    );
+}
+
 fn start_joining(
     address_string: String,
     port_string: String,
@@ -241,7 +355,15 @@ fn start_joining(
     let (sender0, receiver0) = mpsc::channel::<JoinedToUIMessage>();
     let (sender1, receiver1) = mpsc::channel::<UIToJoinedMessage>();
 
-    std::thread::spawn(move || crate::join::join(address, port, sender0, receiver1));
+    std::thread::spawn(move || {
+        crate::join::join(
+            address,
+            port,
+            ImpairmentProfile::from_env(),
+            sender0,
+            receiver1,
+        )
+    });
 
     let mut state_clone = state.clone();
     libadwaita::glib::timeout_add_local(Duration::from_millis(100), move || {
@@ -258,48 +380,122 @@ fn listen_for_message(state: &mut JoinState) {
         return;
     }
     let receiver = receiver.as_mut().unwrap();
-    if let Ok(message) = receiver.try_recv() {
+    while let Ok(message) = receiver.try_recv() {
         match message {
-            JoinedToUIMessage::JoinRequestResponse(accepted) => {
-                handle_join_request_response(accepted, state)
+            JoinedToUIMessage::JoinRequestResponse(response) => {
+                handle_join_request_response(response, state)
             }
-            JoinedToUIMessage::Frame(frame) => handle_frame(frame, state),
+            JoinedToUIMessage::Frame(feed, frame) => handle_frame(feed, frame, state),
+            JoinedToUIMessage::StreamParameters(feed, stream_parameters) => {
+                handle_stream_parameters(feed, stream_parameters, state)
+            }
+            JoinedToUIMessage::Cursor(cursor) => handle_cursor(cursor, state),
+            JoinedToUIMessage::HostLost => handle_host_lost(state),
         }
     }
 }
 
-fn handle_join_request_response(accepted: bool, state: &JoinState) {
+fn handle_join_request_response(
+    response: Result<Vec<StreamParameters>, RefusalReason>,
+    state: &JoinState,
+) {
     state.join_request_response_dialog.clone().choose(
         &state.parent_widget,
         None::<&Cancellable>,
         |_| {},
     );
-    if accepted {
-        state
-            .join_request_response_dialog
-            .set_heading(Some("Accepted"));
-        state
-            .join_request_response_dialog
-            .set_body("You were accepted");
-        state.parent_widget.set_visible_child_name("joined-page");
-    } else {
-        state
-            .join_request_response_dialog
-            .set_heading(Some("Refused"));
-        state
-            .join_request_response_dialog
-            .set_body("You were refused");
-        state
-            .message_sender
-            .borrow()
-            .clone()
-            .unwrap()
-            .send(UIToJoinedMessage::Leave)
-            .unwrap();
-        state.parent_widget.set_visible_child_name("join-page");
+    match response {
+        Ok(feeds) => {
+            state
+                .join_request_response_dialog
+                .set_heading(Some("Accepted"));
+            let description: Vec<String> = feeds
+                .iter()
+                .map(|stream_parameters| {
+                    format!(
+                        "{}x{} {}",
+                        stream_parameters.width,
+                        stream_parameters.height,
+                        stream_parameters.codec.name()
+                    )
+                })
+                .collect();
+            state.join_request_response_dialog.set_body(&format!(
+                "You were accepted, the host streams {}",
+                description.join(", ")
+            ));
+
+            let mut feed_names: Vec<String> = (1..=feeds.len())
+                .map(|feed| format!("Feed {}", feed))
+                .collect();
+            feed_names.push("All feeds".to_string());
+            let feed_names: Vec<&str> = feed_names.iter().map(String::as_str).collect();
+            state
+                .current_frames
+                .replace((0..feeds.len()).map(|_| None).collect());
+            state.feeds.replace(feeds);
+            state
+                .feed_dropdown
+                .set_model(Some(&StringList::new(&feed_names)));
+            state.feed_dropdown.set_selected(0);
+            state.feed_dropdown.set_visible(feed_names.len() > 2);
+            fit_shown_feed(state);
+            state.parent_widget.set_visible_child_name("joined-page");
+        }
+        Err(reason) => {
+            state
+                .join_request_response_dialog
+                .set_heading(Some("Refused"));
+            state
+                .join_request_response_dialog
+                .set_body(&reason.to_string());
+            state
+                .message_sender
+                .borrow()
+                .clone()
+                .unwrap()
+                .send(UIToJoinedMessage::Leave)
+                .unwrap();
+            state.parent_widget.set_visible_child_name("join-page");
+        }
     }
 }
 
-fn handle_frame(frame: NetworkFrame, state: &mut JoinState) {
-    state.current_frame.replace(Some(frame.into()));
+fn handle_host_lost(state: &JoinState) {
+    state
+        .join_request_response_dialog
+        .set_heading(Some("Connection lost"));
+    state
+        .join_request_response_dialog
+        .set_body("The host stopped answering, it may have stopped hosting or lost its connection");
+    state.join_request_response_dialog.clone().choose(
+        &state.parent_widget,
+        None::<&Cancellable>,
+        |_| {},
+    );
+    state.parent_widget.set_visible_child_name("join-page");
+}
+
+fn handle_stream_parameters(feed: FeedID, stream_parameters: StreamParameters, state: &JoinState) {
+    if let Some(feed) = state.feeds.borrow_mut().get_mut(feed.0 as usize) {
+        *feed = stream_parameters;
+    }
+    fit_shown_feed(state);
+}
+
+fn handle_frame(feed: FeedID, frame: DecodedFrame, state: &mut JoinState) {
+    if let Some(current_frame) = state.current_frames.borrow_mut().get_mut(feed.0 as usize) {
+        *current_frame = Some(frame.into());
+    }
+    state.drawing_area.queue_draw();
+}
+
+fn handle_cursor(update: CursorUpdate, state: &JoinState) {
+    let mut cursor = state.cursor.borrow_mut();
+    cursor.position = update.position;
+    if let Some(bitmap) = update.bitmap {
+        cursor.hotspot = bitmap.hotspot;
+        cursor.image = Some(bitmap.into());
+    }
+    state.drawing_area.queue_draw();
 }